@@ -3,7 +3,10 @@
 //! A command-line tool for converting images to/from SIXEL format.
 
 use clap::{Parser, Subcommand, ValueEnum};
-use icy_sixel::{BackgroundMode, EncodeOptions, PixelAspectRatio, QuantizeMethod, SixelImage};
+use icy_sixel::{
+    sixel_bytes, BackgroundMode, ByteBudget, ColorMode, ColorSpace, Dither, EncodeOptions, Encoder, GrayLevels, KmeansOptions, OutputFraming, PaletteFormat,
+    Passthrough, PixelAspectRatio, PrinterOptions, QuantizeMethod, Resize, ResizeFilter, Rgb, SixelImage,
+};
 use image::codecs::gif::GifDecoder;
 use image::{AnimationDecoder, ImageDecoder};
 use std::fs::File;
@@ -21,15 +24,188 @@ enum QuantizeMethodArg {
     Kmeans,
 }
 
-impl From<QuantizeMethodArg> for QuantizeMethod {
-    fn from(arg: QuantizeMethodArg) -> Self {
+/// Build the quantize method from `--method` and the k-means `--seed`, `--sampling-factor` and `--max-samples` arguments.
+fn quantize_method_arg(method: QuantizeMethodArg, seed: u64, sampling_factor: f32, max_samples: u32) -> QuantizeMethod {
+    match method {
+        QuantizeMethodArg::Wu => QuantizeMethod::Wu,
+        QuantizeMethodArg::Kmeans => QuantizeMethod::Kmeans(KmeansOptions::new().seed(seed).sampling_factor(sampling_factor).max_samples(max_samples)),
+    }
+}
+
+/// CLI argument wrapper for Dither
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum DitherArg {
+    /// No dithering
+    None,
+    /// Floyd-Steinberg error diffusion (default)
+    #[default]
+    FloydSteinberg,
+    /// Atkinson error diffusion
+    Atkinson,
+    /// Jarvis-Judice-Ninke error diffusion
+    JarvisJudiceNinke,
+    /// Stucki error diffusion
+    Stucki,
+    /// Sierra error diffusion
+    Sierra,
+    /// Ordered 2x2 Bayer matrix
+    Bayer2x2,
+    /// Ordered 4x4 Bayer matrix
+    Bayer4x4,
+    /// Ordered 8x8 Bayer matrix (good for UI screenshots and pixel art)
+    Bayer8x8,
+    /// Ordered blue noise
+    BlueNoise,
+}
+
+impl From<DitherArg> for Dither {
+    fn from(arg: DitherArg) -> Self {
+        match arg {
+            DitherArg::None => Dither::None,
+            DitherArg::FloydSteinberg => Dither::FloydSteinberg,
+            DitherArg::Atkinson => Dither::Atkinson,
+            DitherArg::JarvisJudiceNinke => Dither::JarvisJudiceNinke,
+            DitherArg::Stucki => Dither::Stucki,
+            DitherArg::Sierra => Dither::Sierra,
+            DitherArg::Bayer2x2 => Dither::Bayer2x2,
+            DitherArg::Bayer4x4 => Dither::Bayer4x4,
+            DitherArg::Bayer8x8 => Dither::Bayer8x8,
+            DitherArg::BlueNoise => Dither::BlueNoise,
+        }
+    }
+}
+
+/// CLI argument wrapper for PaletteFormat
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum PaletteFormatArg {
+    /// RGB percent color definitions (default)
+    #[default]
+    Rgb,
+    /// HLS color definitions
+    Hls,
+}
+
+impl From<PaletteFormatArg> for PaletteFormat {
+    fn from(arg: PaletteFormatArg) -> Self {
+        match arg {
+            PaletteFormatArg::Rgb => PaletteFormat::Rgb,
+            PaletteFormatArg::Hls => PaletteFormat::Hls,
+        }
+    }
+}
+
+/// CLI argument wrapper for ColorSpace
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum ColorSpaceArg {
+    /// Gamma encoded sRGB
+    Srgb,
+    /// Linear light RGB
+    Linear,
+    /// Oklab (default)
+    #[default]
+    Oklab,
+    /// CIELAB
+    Lab,
+}
+
+impl From<ColorSpaceArg> for ColorSpace {
+    fn from(arg: ColorSpaceArg) -> Self {
+        match arg {
+            ColorSpaceArg::Srgb => ColorSpace::Srgb,
+            ColorSpaceArg::Linear => ColorSpace::LinearRgb,
+            ColorSpaceArg::Oklab => ColorSpace::Oklab,
+            ColorSpaceArg::Lab => ColorSpace::Lab,
+        }
+    }
+}
+
+/// CLI argument wrapper for Passthrough
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum PassthroughArg {
+    /// tmux (needs `allow-passthrough on`)
+    Tmux,
+    /// GNU screen
+    Screen,
+}
+
+impl From<PassthroughArg> for Passthrough {
+    fn from(arg: PassthroughArg) -> Self {
         match arg {
-            QuantizeMethodArg::Wu => QuantizeMethod::Wu,
-            QuantizeMethodArg::Kmeans => QuantizeMethod::kmeans(),
+            PassthroughArg::Tmux => Passthrough::Tmux,
+            PassthroughArg::Screen => Passthrough::Screen,
         }
     }
 }
 
+/// CLI argument wrapper for ResizeFilter
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+enum ResizeFilterArg {
+    /// Nearest neighbor (best for pixel art)
+    Nearest,
+    /// Bilinear filter
+    Bilinear,
+    /// Box filter
+    Box,
+    /// Lanczos3 filter (default, sharp downscaling)
+    #[default]
+    Lanczos3,
+}
+
+impl From<ResizeFilterArg> for ResizeFilter {
+    fn from(arg: ResizeFilterArg) -> Self {
+        match arg {
+            ResizeFilterArg::Nearest => ResizeFilter::Nearest,
+            ResizeFilterArg::Bilinear => ResizeFilter::Bilinear,
+            ResizeFilterArg::Box => ResizeFilter::Box,
+            ResizeFilterArg::Lanczos3 => ResizeFilter::Lanczos3,
+        }
+    }
+}
+
+/// Build the resize option from the `--max-width`, `--max-height` and `--scale` arguments.
+fn resize_arg(max_width: Option<usize>, max_height: Option<usize>, scale: Option<u32>) -> Option<Resize> {
+    if let Some(factor) = scale {
+        return Some(Resize::Scale(factor.max(1)));
+    }
+    if max_width.is_none() && max_height.is_none() {
+        return None;
+    }
+    Some(Resize::Fit {
+        max_width: max_width.unwrap_or(usize::MAX),
+        max_height: max_height.unwrap_or(usize::MAX),
+    })
+}
+
+/// Build the output framing from the `--passthrough`, `--c1` and `--line-length` arguments.
+fn framing_arg(passthrough: Option<PassthroughArg>, c1: bool, line_length: Option<usize>) -> OutputFraming {
+    OutputFraming {
+        passthrough: passthrough.map(Into::into),
+        c1_controls: c1,
+        line_length,
+    }
+}
+
+/// Build the color mode from the `--monochrome`, `--invert`, `--grayscale`, `--histogram-levels` and `--high-color` arguments.
+fn color_mode_arg(monochrome: bool, invert: bool, grayscale: Option<u16>, histogram_levels: bool, high_color: Option<usize>) -> ColorMode {
+    match (grayscale, high_color) {
+        _ if monochrome => ColorMode::Monochrome { invert },
+        (Some(levels), _) if histogram_levels => ColorMode::Grayscale(GrayLevels::Histogram(levels)),
+        (Some(levels), _) => ColorMode::Grayscale(GrayLevels::Even(levels)),
+        (None, Some(bands_per_palette)) => ColorMode::HighColor { bands_per_palette },
+        (None, None) => ColorMode::Color,
+    }
+}
+
+/// Parse a hex color like `ffffff` or `#1e1e2e`.
+fn parse_rgb(arg: &str) -> Result<Rgb, String> {
+    let hex = arg.strip_prefix('#').unwrap_or(arg);
+    let value = u32::from_str_radix(hex, 16)
+        .ok()
+        .filter(|_| hex.len() == 6)
+        .ok_or(format!("'{arg}' is not a hex color like ffffff"))?;
+    Ok(Rgb::new((value >> 16) as u8, (value >> 8) as u8, value as u8))
+}
+
 /// CLI argument wrapper for PixelAspectRatio
 #[derive(Clone, Copy, Debug, Default, ValueEnum)]
 enum AspectRatioArg {
@@ -103,14 +279,111 @@ enum Commands {
         #[arg(short, long, default_value = "256")]
         colors: u16,
 
-        /// Floyd-Steinberg error diffusion strength (0.0-1.0, default: 0.875)
+        /// Dithering strength (0.0-1.0, default: 0.875)
         #[arg(short, long, default_value = "0.875")]
         diffusion: f32,
 
+        /// Dithering algorithm
+        #[arg(long, default_value = "floyd-steinberg", value_enum)]
+        dither: DitherArg,
+
+        /// Vary the dithering strength with the content: none on flat areas and text, full on gradients
+        #[arg(long)]
+        adaptive_dither: bool,
+
         /// Color quantization method
         #[arg(short = 'm', long, default_value = "wu", value_enum)]
         method: QuantizeMethodArg,
 
+        /// K-means random seed (output is reproducible for a given seed)
+        #[arg(long, default_value = "0")]
+        seed: u64,
+
+        /// K-means: proportion of the pixels to sample (lower is faster)
+        #[arg(long, default_value = "1.0")]
+        sampling_factor: f32,
+
+        /// K-means: maximum number of pixels to sample
+        #[arg(long, default_value = "262144")]
+        max_samples: u32,
+
+        /// Color space for palette computation and dithering
+        #[arg(long, default_value = "oklab", value_enum)]
+        color_space: ColorSpaceArg,
+
+        /// Spend extra effort on producing a smaller SIXEL stream
+        #[arg(long)]
+        optimize_size: bool,
+
+        /// Color format of the palette definitions
+        #[arg(long, default_value = "rgb", value_enum)]
+        palette_format: PaletteFormatArg,
+
+        /// Wrap the output for a terminal multiplexer
+        #[arg(long, value_enum)]
+        passthrough: Option<PassthroughArg>,
+
+        /// Use 8-bit C1 controls (0x90/0x9C), written as raw bytes
+        #[arg(long)]
+        c1: bool,
+
+        /// Break the SIXEL data into lines of at most this many characters
+        #[arg(long)]
+        line_length: Option<usize>,
+
+        /// Shrink the image to at most this width (preserves aspect ratio)
+        #[arg(long, conflicts_with = "scale")]
+        max_width: Option<usize>,
+
+        /// Shrink the image to at most this height (preserves aspect ratio)
+        #[arg(long, conflicts_with = "scale")]
+        max_height: Option<usize>,
+
+        /// Scale the image by an integer factor (pixel art)
+        #[arg(long)]
+        scale: Option<u32>,
+
+        /// Resampling filter for --max-width, --max-height and --scale
+        #[arg(long, default_value = "lanczos3", value_enum)]
+        filter: ResizeFilterArg,
+
+        /// Encode a 1-bit monochrome image without color definitions
+        #[arg(long)]
+        monochrome: bool,
+
+        /// With --monochrome, set bits for dark pixels instead of light ones (use for printers)
+        #[arg(long, requires = "monochrome")]
+        invert: bool,
+
+        /// Encode a grayscale image with this many gray levels (2-256)
+        #[arg(long, conflicts_with = "monochrome")]
+        grayscale: Option<u16>,
+
+        /// With --grayscale, place the gray levels by the image's histogram instead of evenly
+        #[arg(long, requires = "grayscale")]
+        histogram_levels: bool,
+
+        /// Use a palette per group of this many bands (6 rows each), redefining color registers
+        /// between bands for more than 256 colors; reports the size compared with a single palette
+        #[arg(long, value_name = "BANDS", conflicts_with_all = ["monochrome", "grayscale"])]
+        high_color: Option<usize>,
+
+        /// Frame the output for a DEC sixel printer (2:1 pixels, form feed)
+        #[arg(long)]
+        printer: bool,
+
+        /// With --printer, shrink images wider than this many pixels
+        #[arg(long, default_value = "800", requires = "printer")]
+        page_width: usize,
+
+        /// Fit the output into this many bytes, lowering colors and dithering as needed
+        #[arg(long)]
+        max_bytes: Option<usize>,
+
+        /// With --max-bytes, also shrink the image if needed
+        #[arg(long, requires = "max_bytes")]
+        allow_downscale: bool,
+
         /// Pixel aspect ratio (how terminals interpret pixel dimensions)
         #[arg(short = 'a', long, default_value = "square", value_enum)]
         aspect_ratio: AspectRatioArg,
@@ -118,6 +391,26 @@ enum Commands {
         /// Background mode (how undrawn pixels are handled)
         #[arg(short = 'b', long, default_value = "transparent", value_enum)]
         background: BackgroundArg,
+
+        /// Always include these hex colors (e.g. 000000,ffffff) in the palette; repeatable
+        #[arg(long = "fixed-color", value_name = "RRGGBB", value_parser = parse_rgb, value_delimiter = ',', conflicts_with_all = ["monochrome", "grayscale"])]
+        fixed_colors: Vec<Rgb>,
+
+        /// Grayscale image of the input's size; brighter regions get more palette colors
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["monochrome", "grayscale"])]
+        weight_map: Option<PathBuf>,
+
+        /// Leave pixels of this hex color (e.g. ffffff) undrawn and send the image opaque with it as background
+        #[arg(long, value_name = "RRGGBB", value_parser = parse_rgb, conflicts_with_all = ["monochrome", "grayscale"])]
+        background_color: Option<Rgb>,
+
+        /// Leave pixels of this hex color (e.g. ff00ff) undrawn, for images without alpha
+        #[arg(long, value_name = "RRGGBB", value_parser = parse_rgb)]
+        transparent_color: Option<Rgb>,
+
+        /// With --transparent-color, also key out colors whose channels differ by at most this much
+        #[arg(long, default_value = "0", requires = "transparent_color")]
+        transparent_tolerance: u8,
     },
 
     /// Play an animated GIF in the terminal using SIXEL
@@ -133,14 +426,74 @@ enum Commands {
         #[arg(short, long, default_value = "256")]
         colors: u16,
 
-        /// Floyd-Steinberg error diffusion strength (0.0-1.0, default: 0.875)
+        /// Dithering strength (0.0-1.0, default: 0.875)
         #[arg(short, long, default_value = "0.875")]
         diffusion: f32,
 
+        /// Dithering algorithm
+        #[arg(long, default_value = "floyd-steinberg", value_enum)]
+        dither: DitherArg,
+
+        /// Vary the dithering strength with the content: none on flat areas and text, full on gradients
+        #[arg(long)]
+        adaptive_dither: bool,
+
         /// Color quantization method
         #[arg(short = 'm', long, default_value = "wu", value_enum)]
         method: QuantizeMethodArg,
 
+        /// K-means random seed (output is reproducible for a given seed)
+        #[arg(long, default_value = "0")]
+        seed: u64,
+
+        /// K-means: proportion of the pixels to sample (lower is faster)
+        #[arg(long, default_value = "1.0")]
+        sampling_factor: f32,
+
+        /// K-means: maximum number of pixels to sample
+        #[arg(long, default_value = "262144")]
+        max_samples: u32,
+
+        /// Color space for palette computation and dithering
+        #[arg(long, default_value = "oklab", value_enum)]
+        color_space: ColorSpaceArg,
+
+        /// Spend extra effort on producing a smaller SIXEL stream
+        #[arg(long)]
+        optimize_size: bool,
+
+        /// Color format of the palette definitions
+        #[arg(long, default_value = "rgb", value_enum)]
+        palette_format: PaletteFormatArg,
+
+        /// Wrap the output for a terminal multiplexer
+        #[arg(long, value_enum)]
+        passthrough: Option<PassthroughArg>,
+
+        /// Use 8-bit C1 controls (0x90/0x9C), written as raw bytes
+        #[arg(long)]
+        c1: bool,
+
+        /// Break the SIXEL data into lines of at most this many characters
+        #[arg(long)]
+        line_length: Option<usize>,
+
+        /// Shrink the image to at most this width (preserves aspect ratio)
+        #[arg(long, conflicts_with = "scale")]
+        max_width: Option<usize>,
+
+        /// Shrink the image to at most this height (preserves aspect ratio)
+        #[arg(long, conflicts_with = "scale")]
+        max_height: Option<usize>,
+
+        /// Scale the image by an integer factor (pixel art)
+        #[arg(long)]
+        scale: Option<u32>,
+
+        /// Resampling filter for --max-width, --max-height and --scale
+        #[arg(long, default_value = "lanczos3", value_enum)]
+        filter: ResizeFilterArg,
+
         /// Pixel aspect ratio (how terminals interpret pixel dimensions)
         #[arg(short = 'a', long, default_value = "square", value_enum)]
         aspect_ratio: AspectRatioArg,
@@ -199,9 +552,38 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             output,
             colors,
             diffusion,
+            dither,
+            adaptive_dither,
             method,
+            seed,
+            sampling_factor,
+            max_samples,
+            color_space,
+            optimize_size,
+            palette_format,
+            passthrough,
+            c1,
+            line_length,
+            max_width,
+            max_height,
+            scale,
+            filter,
+            monochrome,
+            invert,
+            grayscale,
+            histogram_levels,
+            high_color,
+            printer,
+            page_width,
+            max_bytes,
+            allow_downscale,
             aspect_ratio,
             background,
+            fixed_colors,
+            weight_map,
+            background_color,
+            transparent_color,
+            transparent_tolerance,
         } => {
             // Read image data from file or stdin
             let (img, source_name) = match &input {
@@ -221,36 +603,91 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let (width, height) = rgba_img.dimensions();
             let pixels = rgba_img.into_raw();
 
+            let weight_map = match &weight_map {
+                Some(path) => {
+                    let weights = image::open(path).map_err(|e| format!("Failed to open '{}': {}", path.display(), e))?.to_luma8();
+                    if weights.dimensions() != (width, height) {
+                        let (weights_width, weights_height) = weights.dimensions();
+                        return Err(format!("Weight map '{}' is {weights_width}x{weights_height}, expected {width}x{height}", path.display()).into());
+                    }
+                    Some(weights.into_raw().into())
+                }
+                None => None,
+            };
+
             info!(
-                "Encoding '{}' ({}x{}) with {} colors, diffusion={:.3}, method={:?}, aspect={:?}, bg={:?}",
+                "Encoding '{}' ({}x{}) with {} colors, diffusion={:.3}, dither={:?}, method={:?}, aspect={:?}, bg={:?}",
                 source_name,
                 width,
                 height,
                 colors.clamp(2, 256),
                 diffusion.clamp(0.0, 1.0),
+                dither,
                 method,
                 aspect_ratio,
                 background
             );
 
-            let opts = EncodeOptions {
-                max_colors: colors.clamp(2, 256),
-                diffusion: diffusion.clamp(0.0, 1.0),
-                quantize_method: method.into(),
-            };
+            let mut opts = EncodeOptions::default();
+            opts.max_colors = colors.clamp(2, 256);
+            opts.fixed_colors = fixed_colors;
+            opts.weight_map = weight_map;
+            opts.diffusion = diffusion.clamp(0.0, 1.0);
+            opts.quantize_method = quantize_method_arg(method, seed, sampling_factor, max_samples);
+            opts.color_space = color_space.into();
+            opts.dither = dither.into();
+            opts.adaptive_diffusion = adaptive_dither;
+            opts.optimize_size = optimize_size;
+            opts.palette_format = palette_format.into();
+            opts.resize = resize_arg(max_width, max_height, scale);
+            opts.resize_filter = filter.into();
+            opts.framing = framing_arg(passthrough, c1, line_length);
+            opts.color_mode = color_mode_arg(monochrome, invert, grayscale, histogram_levels, high_color);
+            opts.printer = printer.then(|| PrinterOptions {
+                page_width,
+                ..Default::default()
+            });
+            opts.transparent_color = transparent_color.map(|key| (key, transparent_tolerance));
+            opts.background_color = background_color;
 
             let image = SixelImage::try_from_rgba(pixels, width as usize, height as usize)?
                 .with_aspect_ratio(aspect_ratio.into())
                 .with_background_mode(background.into());
-            let sixel = image.encode_with(&opts)?;
+            let sixel = match max_bytes {
+                Some(max_bytes) => {
+                    let budget = ByteBudget {
+                        allow_downscale,
+                        ..ByteBudget::new(max_bytes)
+                    };
+                    let encoded = image.encode_to_budget(budget, &opts)?;
+                    info!(
+                        "Fit into {} bytes with {} colors, diffusion={:.3}, resize={:?}",
+                        max_bytes, encoded.options.max_colors, encoded.options.diffusion, encoded.options.resize
+                    );
+                    encoded.sixel
+                }
+                None => match high_color {
+                    Some(bands_per_palette) => {
+                        let encoded = image.encode_high_color(bands_per_palette, &opts)?;
+                        info!(
+                            "High color: {} bytes, {:+.1}% compared with a single palette ({} bytes)",
+                            encoded.sixel.len(),
+                            (encoded.size_ratio() - 1.0) * 100.0,
+                            encoded.single_palette_bytes
+                        );
+                        encoded.sixel
+                    }
+                    None => image.encode_with(&opts)?,
+                },
+            };
 
             match output {
                 Some(path) => {
-                    fs::write(&path, &sixel)?;
+                    fs::write(&path, sixel_bytes(&sixel))?;
                     info!("Written {} bytes to '{}'", sixel.len(), path.display());
                 }
                 None => {
-                    io::stdout().write_all(sixel.as_bytes())?;
+                    io::stdout().write_all(&sixel_bytes(&sixel))?;
                     io::stdout().flush()?;
                 }
             }
@@ -261,7 +698,22 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             output,
             colors,
             diffusion,
+            dither,
+            adaptive_dither,
             method,
+            seed,
+            sampling_factor,
+            max_samples,
+            color_space,
+            optimize_size,
+            palette_format,
+            passthrough,
+            c1,
+            line_length,
+            max_width,
+            max_height,
+            scale,
+            filter,
             aspect_ratio,
             background,
             loops,
@@ -287,11 +739,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 return Err("GIF has no frames".into());
             }
 
-            let opts = EncodeOptions {
-                max_colors: colors.clamp(2, 256),
-                diffusion: diffusion.clamp(0.0, 1.0),
-                quantize_method: method.into(),
-            };
+            let mut opts = EncodeOptions::default();
+            opts.max_colors = colors.clamp(2, 256);
+            opts.diffusion = diffusion.clamp(0.0, 1.0);
+            opts.quantize_method = quantize_method_arg(method, seed, sampling_factor, max_samples);
+            opts.color_space = color_space.into();
+            opts.dither = dither.into();
+            opts.adaptive_diffusion = adaptive_dither;
+            opts.optimize_size = optimize_size;
+            opts.palette_format = palette_format.into();
+            opts.resize = resize_arg(max_width, max_height, scale);
+            opts.resize_filter = filter.into();
+            opts.framing = framing_arg(passthrough, c1, line_length);
 
             // Single frame extraction mode
             if let Some(frame_idx) = frame {
@@ -319,11 +778,11 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                 match output {
                     Some(path) => {
-                        fs::write(&path, &sixel)?;
+                        fs::write(&path, sixel_bytes(&sixel))?;
                         info!("Written frame {} to '{}'", frame_idx, path.display());
                     }
                     None => {
-                        io::stdout().write_all(sixel.as_bytes())?;
+                        io::stdout().write_all(&sixel_bytes(&sixel))?;
                         io::stdout().flush()?;
                     }
                 }
@@ -340,8 +799,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 speed
             );
 
-            // Pre-encode all frames to SIXEL
+            // Pre-encode all frames to SIXEL, reusing the encoder's buffers
             let total_frames = frames.len();
+            let mut encoder = Encoder::new(opts);
             let encoded_frames: Vec<(String, Duration)> = frames
                 .iter()
                 .enumerate()
@@ -353,7 +813,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let image = SixelImage::try_from_rgba(pixels.to_vec(), w as usize, h as usize)?
                         .with_aspect_ratio(aspect_ratio.into())
                         .with_background_mode(background.into());
-                    let sixel = image.encode_with(&opts)?;
+                    let sixel = encoder.encode(&image)?.to_owned();
 
                     // Get frame delay (in milliseconds, apply speed multiplier)
                     let delay = frame.delay().numer_denom_ms();
@@ -384,7 +844,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     file_output.push_str(sixel);
                 }
 
-                fs::write(path, &file_output)?;
+                fs::write(path, sixel_bytes(&file_output))?;
                 info!("Written {} bytes ({} frames) to '{}'", file_output.len(), encoded_frames.len(), path.display());
             } else {
                 // Terminal playback mode
@@ -403,7 +863,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             stdout.write_all(SAVE_CURSOR.as_bytes())?;
                         }
 
-                        stdout.write_all(sixel.as_bytes())?;
+                        stdout.write_all(&sixel_bytes(sixel))?;
                         stdout.flush()?;
 
                         thread::sleep(*delay);
@@ -1,5 +1,5 @@
 use criterion::{criterion_group, criterion_main, Criterion};
-use icy_sixel::{EncodeOptions, QuantizeMethod, SixelImage};
+use icy_sixel::{EncodeOptions, Encoder, IndexedImage, QuantizeMethod, Rgb, SixelImage};
 use std::hint::black_box;
 
 fn load_test_page_png() -> (Vec<u8>, usize, usize) {
@@ -62,15 +62,47 @@ fn bench_encode_beelitz(c: &mut Criterion) {
     });
 }
 
+fn bench_encode_beelitz_optimize_size(c: &mut Criterion) {
+    let (rgba, width, height) = load_beelitz_png();
+
+    let mut opts = EncodeOptions::default();
+    opts.optimize_size = true;
+
+    c.bench_function("encode_beelitz_optimize_size", |b| {
+        b.iter(|| {
+            let image = SixelImage::from_rgba(black_box(rgba.clone()), width, height);
+            let result = image.encode_with(&opts);
+            assert!(result.is_ok());
+            result
+        })
+    });
+}
+
+/// Band writing alone: the Beelitz photo mapped to a fixed 3-3-2 palette, so
+/// no quantization runs. Compare with `--features rayon` for the parallel path.
+fn bench_encode_beelitz_indexed(c: &mut Criterion) {
+    let (rgba, width, height) = load_beelitz_png();
+    let palette: Vec<Rgb> = (0..=255u8).map(|i| Rgb::new((i >> 5) * 36, (i >> 2 & 7) * 36, (i & 3) * 85)).collect();
+    let indices: Vec<u8> = rgba.chunks_exact(4).map(|c| (c[0] >> 5) << 5 | (c[1] >> 5) << 2 | c[2] >> 6).collect();
+    let image = IndexedImage::from_indexed(palette, indices, width, height, None);
+
+    c.bench_function("encode_beelitz_indexed", |b| {
+        b.iter(|| {
+            let result = black_box(&image).encode();
+            assert!(result.is_ok());
+            result
+        })
+    });
+}
+
 // Quantizer comparison benchmarks
 fn bench_quantizer_wu(c: &mut Criterion) {
     let (rgba, width, height) = load_beelitz_png();
 
-    let opts = EncodeOptions {
-        max_colors: 256,
-        diffusion: 0.875,
-        quantize_method: QuantizeMethod::Wu,
-    };
+    let mut opts = EncodeOptions::default();
+    opts.max_colors = 256;
+    opts.diffusion = 0.875;
+    opts.quantize_method = QuantizeMethod::Wu;
 
     c.bench_function("quantizer_wu_256colors", |b| {
         b.iter(|| {
@@ -85,11 +117,10 @@ fn bench_quantizer_wu(c: &mut Criterion) {
 fn bench_quantizer_kmeans(c: &mut Criterion) {
     let (rgba, width, height) = load_beelitz_png();
 
-    let opts = EncodeOptions {
-        max_colors: 256,
-        diffusion: 0.875,
-        quantize_method: QuantizeMethod::kmeans(),
-    };
+    let mut opts = EncodeOptions::default();
+    opts.max_colors = 256;
+    opts.diffusion = 0.875;
+    opts.quantize_method = QuantizeMethod::kmeans();
 
     c.bench_function("quantizer_kmeans_256colors", |b| {
         b.iter(|| {
@@ -105,11 +136,10 @@ fn bench_quantizer_kmeans(c: &mut Criterion) {
 fn bench_colors_256(c: &mut Criterion) {
     let (rgba, width, height) = load_beelitz_png();
 
-    let opts = EncodeOptions {
-        max_colors: 256,
-        diffusion: 0.875,
-        quantize_method: QuantizeMethod::Wu,
-    };
+    let mut opts = EncodeOptions::default();
+    opts.max_colors = 256;
+    opts.diffusion = 0.875;
+    opts.quantize_method = QuantizeMethod::Wu;
 
     c.bench_function("colors_256", |b| {
         b.iter(|| {
@@ -124,11 +154,10 @@ fn bench_colors_256(c: &mut Criterion) {
 fn bench_colors_16(c: &mut Criterion) {
     let (rgba, width, height) = load_beelitz_png();
 
-    let opts = EncodeOptions {
-        max_colors: 16,
-        diffusion: 0.875,
-        quantize_method: QuantizeMethod::Wu,
-    };
+    let mut opts = EncodeOptions::default();
+    opts.max_colors = 16;
+    opts.diffusion = 0.875;
+    opts.quantize_method = QuantizeMethod::Wu;
 
     c.bench_function("colors_16", |b| {
         b.iter(|| {
@@ -143,11 +172,10 @@ fn bench_colors_16(c: &mut Criterion) {
 fn bench_colors_2(c: &mut Criterion) {
     let (rgba, width, height) = load_beelitz_png();
 
-    let opts = EncodeOptions {
-        max_colors: 2,
-        diffusion: 0.875,
-        quantize_method: QuantizeMethod::Wu,
-    };
+    let mut opts = EncodeOptions::default();
+    opts.max_colors = 2;
+    opts.diffusion = 0.875;
+    opts.quantize_method = QuantizeMethod::Wu;
 
     c.bench_function("colors_2", |b| {
         b.iter(|| {
@@ -163,11 +191,10 @@ fn bench_colors_2(c: &mut Criterion) {
 fn bench_diffusion_off(c: &mut Criterion) {
     let (rgba, width, height) = load_beelitz_png();
 
-    let opts = EncodeOptions {
-        max_colors: 256,
-        diffusion: 0.0,
-        quantize_method: QuantizeMethod::Wu,
-    };
+    let mut opts = EncodeOptions::default();
+    opts.max_colors = 256;
+    opts.diffusion = 0.0;
+    opts.quantize_method = QuantizeMethod::Wu;
 
     c.bench_function("diffusion_off", |b| {
         b.iter(|| {
@@ -182,11 +209,10 @@ fn bench_diffusion_off(c: &mut Criterion) {
 fn bench_diffusion_low(c: &mut Criterion) {
     let (rgba, width, height) = load_beelitz_png();
 
-    let opts = EncodeOptions {
-        max_colors: 256,
-        diffusion: 0.3,
-        quantize_method: QuantizeMethod::Wu,
-    };
+    let mut opts = EncodeOptions::default();
+    opts.max_colors = 256;
+    opts.diffusion = 0.3;
+    opts.quantize_method = QuantizeMethod::Wu;
 
     c.bench_function("diffusion_low", |b| {
         b.iter(|| {
@@ -201,11 +227,10 @@ fn bench_diffusion_low(c: &mut Criterion) {
 fn bench_diffusion_medium(c: &mut Criterion) {
     let (rgba, width, height) = load_beelitz_png();
 
-    let opts = EncodeOptions {
-        max_colors: 256,
-        diffusion: 0.5,
-        quantize_method: QuantizeMethod::Wu,
-    };
+    let mut opts = EncodeOptions::default();
+    opts.max_colors = 256;
+    opts.diffusion = 0.5;
+    opts.quantize_method = QuantizeMethod::Wu;
 
     c.bench_function("diffusion_medium", |b| {
         b.iter(|| {
@@ -220,11 +245,10 @@ fn bench_diffusion_medium(c: &mut Criterion) {
 fn bench_diffusion_full(c: &mut Criterion) {
     let (rgba, width, height) = load_beelitz_png();
 
-    let opts = EncodeOptions {
-        max_colors: 256,
-        diffusion: 0.875,
-        quantize_method: QuantizeMethod::Wu,
-    };
+    let mut opts = EncodeOptions::default();
+    opts.max_colors = 256;
+    opts.diffusion = 0.875;
+    opts.quantize_method = QuantizeMethod::Wu;
 
     c.bench_function("diffusion_full", |b| {
         b.iter(|| {
@@ -264,10 +288,25 @@ fn bench_encode_medium(c: &mut Criterion) {
     });
 }
 
+fn bench_encoder_reuse(c: &mut Criterion) {
+    let image = SixelImage::from_rgba(generate_gradient_rgba(200, 200), 200, 200);
+    let mut encoder = Encoder::new(EncodeOptions::default());
+
+    c.bench_function("encode_gradient_200x200_reused_encoder", |b| {
+        b.iter(|| {
+            let result = encoder.encode(black_box(&image));
+            assert!(result.is_ok());
+            result.map(str::len)
+        })
+    });
+}
+
 criterion_group!(
     benches,
     bench_encode_test_page,
     bench_encode_beelitz,
+    bench_encode_beelitz_optimize_size,
+    bench_encode_beelitz_indexed,
     // Quantizer comparison
     bench_quantizer_wu,
     bench_quantizer_kmeans,
@@ -283,5 +322,6 @@ criterion_group!(
     // Synthetic benchmarks
     bench_encode_small,
     bench_encode_medium,
+    bench_encoder_reuse,
 );
 criterion_main!(benches);
@@ -0,0 +1,328 @@
+//! Vectorized kernels of the band writer.
+//!
+//! Two loops dominate writing the bands of a large image: scattering the
+//! pixels of a band into the per-color sixel rows and finding the runs of
+//! identical sixels for `!` repeats. Both have SSE2/AVX2 versions on x86 and
+//! a NEON version on aarch64, picked at runtime like the decoder's span fill,
+//! and a scalar fallback that produces the same results.
+
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::{
+    __m128i, __m256i, _mm256_and_si256, _mm256_cmpeq_epi8, _mm256_loadu_si256, _mm256_movemask_epi8, _mm256_or_si256, _mm256_set1_epi8, _mm256_storeu_si256,
+    _mm_and_si128, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_or_si128, _mm_set1_epi8, _mm_storeu_si128,
+};
+
+#[cfg(target_arch = "x86")]
+use core::arch::x86::{
+    __m128i, __m256i, _mm256_and_si256, _mm256_cmpeq_epi8, _mm256_loadu_si256, _mm256_movemask_epi8, _mm256_or_si256, _mm256_set1_epi8, _mm256_storeu_si256,
+    _mm_and_si128, _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_or_si128, _mm_set1_epi8, _mm_storeu_si128,
+};
+
+#[cfg(target_arch = "aarch64")]
+use core::arch::aarch64::{
+    vandq_u8, vceqq_u8, vdupq_n_u8, vget_lane_u64, vld1q_u8, vorrq_u8, vreinterpret_u64_u8, vreinterpretq_u16_u8, vshrn_n_u16, vst1q_u8,
+};
+
+/// Length of the run of `row[start]` that begins at `start`, at least 1.
+pub(crate) fn run_length(row: &[u8], start: usize) -> usize {
+    debug_assert!(start < row.len());
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+    {
+        if row.len() - start > 32 && std::is_x86_feature_detected!("avx2") {
+            return unsafe { run_length_avx2(row, start) };
+        }
+        #[cfg(target_arch = "x86")]
+        {
+            if !std::is_x86_feature_detected!("sse2") {
+                return run_length_scalar(row, start);
+            }
+        }
+        unsafe { run_length_sse2(row, start) }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        unsafe { run_length_neon(row, start) }
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+    {
+        run_length_scalar(row, start)
+    }
+}
+
+fn run_length_scalar(row: &[u8], start: usize) -> usize {
+    let bits = row[start];
+    row[start + 1..].iter().position(|&b| b != bits).map_or(row.len() - start, |offset| offset + 1)
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+unsafe fn run_length_sse2(row: &[u8], start: usize) -> usize {
+    let needle = _mm_set1_epi8(row[start] as i8);
+    let mut x = start + 1;
+    while x + 16 <= row.len() {
+        let equal = _mm_movemask_epi8(_mm_cmpeq_epi8(_mm_loadu_si128(row.as_ptr().add(x) as *const __m128i), needle)) as u32;
+        if equal != 0xffff {
+            return x + (!equal).trailing_zeros() as usize - start;
+        }
+        x += 16;
+    }
+    x - 1 + run_length_scalar(row, x - 1) - start
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+#[target_feature(enable = "avx2")]
+unsafe fn run_length_avx2(row: &[u8], start: usize) -> usize {
+    let needle = _mm256_set1_epi8(row[start] as i8);
+    let mut x = start + 1;
+    while x + 32 <= row.len() {
+        let equal = _mm256_movemask_epi8(_mm256_cmpeq_epi8(_mm256_loadu_si256(row.as_ptr().add(x) as *const __m256i), needle)) as u32;
+        if equal != u32::MAX {
+            return x + (!equal).trailing_zeros() as usize - start;
+        }
+        x += 32;
+    }
+    x - 1 + run_length_scalar(row, x - 1) - start
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn run_length_neon(row: &[u8], start: usize) -> usize {
+    let needle = vdupq_n_u8(row[start]);
+    let mut x = start + 1;
+    while x + 16 <= row.len() {
+        let equal = vceqq_u8(vld1q_u8(row.as_ptr().add(x)), needle);
+        // Narrow the 16 byte lanes to 4 bits each, NEON has no movemask.
+        let equal = vget_lane_u64::<0>(vreinterpret_u64_u8(vshrn_n_u16::<4>(vreinterpretq_u16_u8(equal))));
+        if equal != u64::MAX {
+            return x + (!equal).trailing_zeros() as usize / 4 - start;
+        }
+        x += 16;
+    }
+    x - 1 + run_length_scalar(row, x - 1) - start
+}
+
+/// For every column of a band, the sixel bits of the drawn pixels that have
+/// the color of the pixel in the top row.
+///
+/// `rows` holds the color indices of the six rows of the band (missing rows
+/// of the last band may repeat any row) and `opaque` the sixel bits of the
+/// drawn pixels of every column. In smooth areas every drawn pixel of a
+/// column usually has the same color, which is then set with a single store.
+pub(crate) fn same_color_bits(rows: &[&[u8]; 6], opaque: &[u8], same: &mut [u8]) {
+    let width = same.len();
+    debug_assert!(opaque.len() >= width && rows.iter().all(|row| row.len() >= width));
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+    {
+        if std::is_x86_feature_detected!("avx2") {
+            let done = unsafe { same_color_bits_avx2(rows, opaque, same) };
+            return same_color_bits_scalar(rows, opaque, same, done);
+        }
+        #[cfg(target_arch = "x86")]
+        {
+            if !std::is_x86_feature_detected!("sse2") {
+                return same_color_bits_scalar(rows, opaque, same, 0);
+            }
+        }
+        let done = unsafe { same_color_bits_sse2(rows, opaque, same) };
+        same_color_bits_scalar(rows, opaque, same, done)
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    {
+        let done = unsafe { same_color_bits_neon(rows, opaque, same) };
+        same_color_bits_scalar(rows, opaque, same, done)
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+    {
+        same_color_bits_scalar(rows, opaque, same, 0)
+    }
+}
+
+/// Scalar [`same_color_bits`] for the columns from `start` on.
+fn same_color_bits_scalar(rows: &[&[u8]; 6], opaque: &[u8], same: &mut [u8], start: usize) {
+    for x in start..same.len() {
+        let top = rows[0][x];
+        let bits = rows.iter().enumerate().fold(0u8, |bits, (k, row)| bits | (u8::from(row[x] == top) << k));
+        same[x] = bits & opaque[x];
+    }
+}
+
+/// Vectorized part of [`same_color_bits`]; returns the number of columns done.
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+unsafe fn same_color_bits_sse2(rows: &[&[u8]; 6], opaque: &[u8], same: &mut [u8]) -> usize {
+    let load = |slice: &[u8], x: usize| _mm_loadu_si128(slice.as_ptr().add(x) as *const __m128i);
+    let mut x = 0;
+    while x + 16 <= same.len() {
+        let top = load(rows[0], x);
+        let mut bits = _mm_set1_epi8(1);
+        for (k, row) in rows.iter().enumerate().skip(1) {
+            bits = _mm_or_si128(bits, _mm_and_si128(_mm_cmpeq_epi8(load(row, x), top), _mm_set1_epi8(1 << k)));
+        }
+        _mm_storeu_si128(same.as_mut_ptr().add(x) as *mut __m128i, _mm_and_si128(bits, load(opaque, x)));
+        x += 16;
+    }
+    x
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "x86"))]
+#[target_feature(enable = "avx2")]
+unsafe fn same_color_bits_avx2(rows: &[&[u8]; 6], opaque: &[u8], same: &mut [u8]) -> usize {
+    let load = |slice: &[u8], x: usize| _mm256_loadu_si256(slice.as_ptr().add(x) as *const __m256i);
+    let mut x = 0;
+    while x + 32 <= same.len() {
+        let top = load(rows[0], x);
+        let mut bits = _mm256_set1_epi8(1);
+        for (k, row) in rows.iter().enumerate().skip(1) {
+            bits = _mm256_or_si256(bits, _mm256_and_si256(_mm256_cmpeq_epi8(load(row, x), top), _mm256_set1_epi8(1 << k)));
+        }
+        _mm256_storeu_si256(same.as_mut_ptr().add(x) as *mut __m256i, _mm256_and_si256(bits, load(opaque, x)));
+        x += 32;
+    }
+    x
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn same_color_bits_neon(rows: &[&[u8]; 6], opaque: &[u8], same: &mut [u8]) -> usize {
+    let mut x = 0;
+    while x + 16 <= same.len() {
+        let top = vld1q_u8(rows[0].as_ptr().add(x));
+        let mut bits = vdupq_n_u8(1);
+        for (k, row) in rows.iter().enumerate().skip(1) {
+            bits = vorrq_u8(bits, vandq_u8(vceqq_u8(vld1q_u8(row.as_ptr().add(x)), top), vdupq_n_u8(1 << k)));
+        }
+        vst1q_u8(same.as_mut_ptr().add(x), vandq_u8(bits, vld1q_u8(opaque.as_ptr().add(x))));
+        x += 16;
+    }
+    x
+}
+
+/// Byte `i` of `SPREAD[b]` is bit `i` of `b`.
+const SPREAD: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut b = 0;
+    while b < 256 {
+        let mut i = 0;
+        while i < 8 {
+            table[b] |= ((b as u64 >> i) & 1) << (8 * i);
+            i += 1;
+        }
+        b += 1;
+    }
+    table
+};
+
+/// Set bit `row` of `opaque[x]` for every set bit `x` of `word`, the
+/// opacity of 64 pixels of that row of a band.
+#[inline]
+pub(crate) fn spread_bits(word: u64, row: usize, opaque: &mut [u8; 64]) {
+    for (i, chunk) in opaque.chunks_exact_mut(8).enumerate() {
+        let spread = SPREAD[(word >> (8 * i)) as usize & 0xff] << row;
+        let bytes = u64::from_le_bytes(chunk.try_into().unwrap()) | spread;
+        chunk.copy_from_slice(&bytes.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Small xorshift generator; runs and equal columns need few distinct values.
+    fn random_bytes(len: usize, seed: u64, values: u8) -> Vec<u8> {
+        let mut state = seed | 1;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                (state % values as u64) as u8
+            })
+            .collect()
+    }
+
+    type RunLengthKernel = fn(&[u8], usize) -> usize;
+    type SameColorKernel = fn(&[&[u8]; 6], &[u8], &mut [u8]);
+
+    /// Every kernel the running CPU supports, the dispatched one included.
+    fn run_length_kernels() -> Vec<(&'static str, RunLengthKernel)> {
+        let mut kernels: Vec<(&'static str, RunLengthKernel)> = vec![("dispatch", run_length)];
+        #[cfg(target_arch = "x86_64")]
+        {
+            kernels.push(("sse2", |row, start| unsafe { run_length_sse2(row, start) }));
+            if std::is_x86_feature_detected!("avx2") {
+                kernels.push(("avx2", |row, start| unsafe { run_length_avx2(row, start) }));
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        kernels.push(("neon", |row, start| unsafe { run_length_neon(row, start) }));
+        kernels
+    }
+
+    fn same_color_kernels() -> Vec<(&'static str, SameColorKernel)> {
+        let mut kernels: Vec<(&'static str, SameColorKernel)> = vec![("dispatch", same_color_bits)];
+        #[cfg(target_arch = "x86_64")]
+        {
+            kernels.push(("sse2", |rows, opaque, same| {
+                let done = unsafe { same_color_bits_sse2(rows, opaque, same) };
+                same_color_bits_scalar(rows, opaque, same, done)
+            }));
+            if std::is_x86_feature_detected!("avx2") {
+                kernels.push(("avx2", |rows, opaque, same| {
+                    let done = unsafe { same_color_bits_avx2(rows, opaque, same) };
+                    same_color_bits_scalar(rows, opaque, same, done)
+                }));
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        kernels.push(("neon", |rows, opaque, same| {
+            let done = unsafe { same_color_bits_neon(rows, opaque, same) };
+            same_color_bits_scalar(rows, opaque, same, done)
+        }));
+        kernels
+    }
+
+    #[test]
+    fn test_run_length_matches_scalar() {
+        for (seed, values) in [(1, 2), (2, 3), (3, 64), (4, 1)] {
+            // Long runs mixed with noise, so both the vector loop and the tail are hit.
+            let mut row = random_bytes(300, seed, values);
+            row[40..140].fill(7);
+            for len in [1, 15, 16, 17, 33, 64, 300] {
+                for start in 0..len {
+                    let expected = run_length_scalar(&row[..len], start);
+                    for (name, kernel) in run_length_kernels() {
+                        assert_eq!(kernel(&row[..len], start), expected, "{name}: seed {seed} len {len} start {start}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_same_color_bits_matches_scalar() {
+        for (seed, width) in [(5, 1), (6, 16), (7, 31), (8, 100), (9, 257)] {
+            let data = random_bytes(width * 7, seed, 3);
+            let rows: Vec<&[u8]> = data.chunks_exact(width).collect();
+            let rows: [&[u8]; 6] = rows[..6].try_into().unwrap();
+            let opaque: Vec<u8> = data[width * 6..].iter().map(|&b| [0x3f, 0x2d, 0x00][b as usize]).collect();
+            let mut expected = vec![0xff; width];
+            same_color_bits_scalar(&rows, &opaque, &mut expected, 0);
+            for (name, kernel) in same_color_kernels() {
+                let mut same = vec![0xff; width];
+                kernel(&rows, &opaque, &mut same);
+                assert_eq!(same, expected, "{name}: seed {seed} width {width}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_spread_bits() {
+        let mut opaque = [0u8; 64];
+        spread_bits(0x8000_0000_0000_0005, 3, &mut opaque);
+        spread_bits(0x1, 0, &mut opaque);
+        assert_eq!((opaque[0], opaque[1], opaque[2], opaque[63]), (9, 0, 8, 8));
+        assert_eq!(opaque.iter().filter(|&&b| b != 0).count(), 3);
+    }
+}
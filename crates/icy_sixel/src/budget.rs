@@ -0,0 +1,169 @@
+//! Encoding under a byte budget.
+//!
+//! The size of a SIXEL stream depends on the image content in ways that are
+//! hard to predict, so the search below encodes for real and measures the
+//! output. The output shrinks with fewer colors, weaker dithering and smaller
+//! images; the search gives up quality in that order.
+
+use crate::{
+    encoder::{sixel_encode_view, ColorMode, EncodeOptions, RgbaView},
+    resize::Resize,
+    BackgroundMode, PixelAspectRatio, Result, SixelError,
+};
+
+/// Number of bisection steps for the image size, so the chosen scale is
+/// within 1/256 of the largest one that fits.
+const SCALE_SEARCH_STEPS: usize = 8;
+
+/// Size limit for [`SixelImage::encode_to_budget`](crate::SixelImage::encode_to_budget).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ByteBudget {
+    /// Maximum size of the encoded SIXEL string in bytes.
+    pub max_bytes: usize,
+    /// Shrink the image if even the fewest colors without dithering don't
+    /// fit (default: `false`).
+    pub allow_downscale: bool,
+}
+
+impl ByteBudget {
+    /// A budget of `max_bytes` that keeps the image size.
+    pub const fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            allow_downscale: false,
+        }
+    }
+}
+
+/// The result of [`SixelImage::encode_to_budget`](crate::SixelImage::encode_to_budget).
+#[derive(Clone, Debug)]
+pub struct BudgetedEncoding {
+    /// The encoded SIXEL string, at most `max_bytes` long.
+    pub sixel: String,
+    /// The options the image was encoded with: the given options with the
+    /// chosen `max_colors`, `diffusion` and `resize`.
+    pub options: EncodeOptions,
+}
+
+/// Encode `view` with the best quality whose output fits `budget`.
+///
+/// At the original size the dithering strength is tried at full, half and
+/// zero strength, each with a binary search for the largest color count that
+/// fits. The most colors win, stronger dithering breaks ties. That is at most
+/// 3 × 9 = 27 encodes.
+///
+/// If nothing fits and downscaling is allowed, the largest scale at which the
+/// fewest colors without dithering fit is bisected in [`SCALE_SEARCH_STEPS`]
+/// steps, one encode each plus one at the smallest size, and the color and
+/// dithering search runs once more at that scale. The worst case is therefore
+/// 27 + 9 + 27 = 63 encodes, most of them of a smaller image.
+pub(crate) fn encode_to_budget(
+    view: RgbaView<'_>,
+    opts: &EncodeOptions,
+    pixel_aspect_ratio: PixelAspectRatio,
+    background_mode: BackgroundMode,
+    budget: ByteBudget,
+) -> Result<BudgetedEncoding> {
+    if let Some(found) = search_colors_and_diffusion(view, opts, pixel_aspect_ratio, background_mode, budget.max_bytes)? {
+        return Ok(found);
+    }
+    let exceeded = SixelError::BudgetExceeded { max_bytes: budget.max_bytes };
+    if !budget.allow_downscale {
+        return Err(exceeded);
+    }
+
+    let (display_width, display_height) = opts.resize.map_or((view.width, view.height), |resize| {
+        resize.target_size(view.width, view.height, PixelAspectRatio::Square)
+    });
+    let scaled = |scale: f64| EncodeOptions {
+        resize: Some(Resize::Exact {
+            width: ((display_width as f64 * scale).round() as usize).max(1),
+            height: ((display_height as f64 * scale).round() as usize).max(1),
+        }),
+        ..opts.clone()
+    };
+    // The cheapest encode at a scale: if this doesn't fit, nothing does.
+    let fits = |scale: f64| -> Result<bool> {
+        let options = EncodeOptions {
+            max_colors: min_colors(opts),
+            diffusion: 0.0,
+            ..scaled(scale)
+        };
+        Ok(sixel_encode_view(view, &options, pixel_aspect_ratio, background_mode)?.len() <= budget.max_bytes)
+    };
+
+    // The output shrinks (almost) monotonically with the image size.
+    let (mut low, mut high) = (1.0 / display_width.max(display_height).max(1) as f64, 1.0);
+    if !fits(low)? {
+        return Err(exceeded);
+    }
+    for _ in 0..SCALE_SEARCH_STEPS {
+        let mid = (low + high) / 2.0;
+        if fits(mid)? {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    search_colors_and_diffusion(view, &scaled(low), pixel_aspect_ratio, background_mode, budget.max_bytes)?.ok_or(exceeded)
+}
+
+/// Fewest colors the search tries; the monochrome and grayscale modes don't
+/// use `max_colors`.
+fn min_colors(opts: &EncodeOptions) -> u16 {
+    if matches!(opts.color_mode, ColorMode::Color | ColorMode::HighColor { .. }) {
+        2
+    } else {
+        opts.max_colors.clamp(2, 256)
+    }
+}
+
+fn search_colors_and_diffusion(
+    view: RgbaView<'_>,
+    opts: &EncodeOptions,
+    pixel_aspect_ratio: PixelAspectRatio,
+    background_mode: BackgroundMode,
+    max_bytes: usize,
+) -> Result<Option<BudgetedEncoding>> {
+    let encode = |max_colors: u16, diffusion: f32| -> Result<Option<BudgetedEncoding>> {
+        let options = EncodeOptions {
+            max_colors,
+            diffusion,
+            ..opts.clone()
+        };
+        let sixel = sixel_encode_view(view, &options, pixel_aspect_ratio, background_mode)?;
+        Ok((sixel.len() <= max_bytes).then_some(BudgetedEncoding { sixel, options }))
+    };
+
+    let max_colors = opts.max_colors.clamp(2, 256);
+    let min_colors = min_colors(opts);
+    let diffusion = opts.diffusion.clamp(0.0, 1.0);
+    let mut levels = vec![diffusion, diffusion / 2.0, 0.0];
+    levels.dedup();
+
+    let mut best: Option<BudgetedEncoding> = None;
+    for diffusion in levels {
+        let low = best.as_ref().map_or(min_colors, |best| best.options.max_colors + 1);
+        if low > max_colors {
+            break;
+        }
+        if let Some(found) = encode(max_colors, diffusion)? {
+            best = Some(found);
+            break;
+        }
+        // Largest fitting color count in low..max_colors; sizes grow
+        // (almost) monotonically with the color count.
+        let (mut low, mut high) = (low, max_colors);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match encode(mid, diffusion)? {
+                Some(found) => {
+                    low = mid + 1;
+                    best = Some(found);
+                }
+                None => high = mid,
+            }
+        }
+    }
+    Ok(best)
+}
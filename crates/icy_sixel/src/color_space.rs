@@ -0,0 +1,175 @@
+//! Working color spaces for palette computation and dithering.
+//!
+//! Palette colors are cluster centers and dithering spreads color differences,
+//! so both depend on the space distances are measured in. quantette itself
+//! works in Oklab; the other spaces go through its generic Wu and k-means
+//! quantizers with a binner sized for the space.
+
+use std::sync::OnceLock;
+
+use quantette::{
+    color_space::{oklab_to_srgb8, srgb8_to_oklab},
+    deps::palette::{
+        cast::{from_array, into_array},
+        FromColor, Lab, LinSrgb, Oklab, Srgb,
+    },
+    kmeans::Kmeans,
+    wu::{BinnerF32x3, WuF32x3},
+    ColorComponents, PaletteSize, QuantizeMethod,
+};
+
+use crate::{Result, SixelError};
+
+/// Color space in which palette colors are computed and dither error is
+/// spread (see [`EncodeOptions::color_space`](crate::EncodeOptions::color_space)).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// Gamma encoded sRGB, the space the pixel values are stored in. Cheap,
+    /// but dark gradients get too few colors and dithered areas come out too
+    /// dark.
+    Srgb,
+    /// Linear light RGB. Dithered areas keep their brightness, but palette
+    /// colors crowd into the highlights.
+    LinearRgb,
+    /// Oklab (default): perceptually uniform, with stable hues.
+    #[default]
+    Oklab,
+    /// CIELAB (D65): the classic perceptual space, less uniform in hue than
+    /// Oklab, notably for blues.
+    Lab,
+}
+
+impl ColorSpace {
+    /// Convert sRGB pixels to this space.
+    pub(crate) fn convert_srgb8(self, colors: &[Srgb<u8>]) -> Vec<[f32; 3]> {
+        match self {
+            Self::Srgb => colors.iter().map(|c| into_array(c.into_format::<f32>())).collect(),
+            Self::LinearRgb => colors.iter().map(|&c| into_array(linear_from_srgb8(c))).collect(),
+            Self::Oklab => srgb8_to_oklab(colors).into_iter().map(into_array).collect(),
+            Self::Lab => colors.iter().map(|&c| into_array(Lab::from_color(linear_from_srgb8(c)))).collect(),
+        }
+    }
+
+    /// Convert colors of this space back to sRGB, clamping out of gamut colors.
+    pub(crate) fn to_srgb8(self, colors: &[[f32; 3]]) -> Vec<Srgb<u8>> {
+        match self {
+            Self::Srgb => colors.iter().map(|&c| srgb8_from_srgb(from_array::<Srgb>(c))).collect(),
+            Self::LinearRgb => colors.iter().map(|&c| srgb8_from_linear(from_array::<LinSrgb>(c))).collect(),
+            Self::Oklab => oklab_to_srgb8(&colors.iter().map(|&c| from_array::<Oklab>(c)).collect::<Vec<_>>()),
+            Self::Lab => colors.iter().map(|&c| srgb8_from_linear(LinSrgb::from_color(from_array::<Lab>(c)))).collect(),
+        }
+    }
+
+    /// Compute a palette of up to `palette_size` colors for `colors` (given in
+    /// this space). The palette is returned in this space as well.
+    pub(crate) fn palette(self, colors: &[[f32; 3]], method: &QuantizeMethod, palette_size: PaletteSize) -> Result<Vec<[f32; 3]>> {
+        match self {
+            Self::Srgb => quantize_components::<Srgb<f32>, 32, 32, 32>(colors, BinnerF32x3::new([(0.0, 1.0); 3]), method, palette_size, self),
+            Self::LinearRgb => quantize_components::<LinSrgb<f32>, 32, 32, 32>(colors, BinnerF32x3::new([(0.0, 1.0); 3]), method, palette_size, self),
+            Self::Oklab => quantize_components::<Oklab, 32, 16, 16>(colors, BinnerF32x3::oklab_from_srgb8(), method, palette_size, self),
+            Self::Lab => quantize_components::<Lab, 32, 16, 16>(colors, BinnerF32x3::new(LAB_COMPONENT_RANGES), method, palette_size, self),
+        }
+    }
+}
+
+/// The range of CIELAB components for colors converted from sRGB.
+const LAB_COMPONENT_RANGES: [(f32, f32); 3] = [(0.0, 100.0), (-87.0, 99.0), (-108.0, 95.0)];
+
+fn quantize_components<Color, const B1: usize, const B2: usize, const B3: usize>(
+    colors: &[[f32; 3]],
+    binner: BinnerF32x3<B1, B2, B3>,
+    method: &QuantizeMethod,
+    palette_size: PaletteSize,
+    space: ColorSpace,
+) -> Result<Vec<[f32; 3]>>
+where
+    Color: ColorComponents<f32, 3>,
+{
+    let colors: Vec<Color> = colors.iter().map(|&c| from_array::<Color>(c)).collect();
+    let wu = || WuF32x3::run_slice(&colors, binner).map_err(|e| SixelError::Quantization(e.to_string()));
+    let palette = match method {
+        QuantizeMethod::CustomPalette(palette) => return Ok(space.convert_srgb8(&palette.to_srgb8())),
+        QuantizeMethod::Wu => wu()?.palette(palette_size),
+        QuantizeMethod::Kmeans(options) => {
+            let centroids = wu()?.palette(palette_size);
+            Kmeans::run_slice(&colors, centroids, *options)
+                .map_err(|e| SixelError::Quantization(e.to_string()))?
+                .into_palette()
+        }
+    };
+    Ok(palette.iter().map(|&c| into_array(c)).collect())
+}
+
+/// Linear light value of an 8-bit sRGB component.
+pub(crate) fn srgb_to_linear(v: u8) -> f32 {
+    static TO_LINEAR: OnceLock<[f32; 256]> = OnceLock::new();
+    let to_linear = TO_LINEAR.get_or_init(|| {
+        std::array::from_fn(|i| {
+            let v = i as f32 / 255.0;
+            if v <= 0.04045 {
+                v / 12.92
+            } else {
+                ((v + 0.055) / 1.055).powf(2.4)
+            }
+        })
+    });
+    to_linear[v as usize]
+}
+
+/// sRGB encoded value (0.0-1.0) of a linear light component.
+pub(crate) fn linear_to_srgb(v: f32) -> f32 {
+    if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn linear_from_srgb8(c: Srgb<u8>) -> LinSrgb<f32> {
+    LinSrgb::new(srgb_to_linear(c.red), srgb_to_linear(c.green), srgb_to_linear(c.blue))
+}
+
+fn srgb8_from_linear(c: LinSrgb<f32>) -> Srgb<u8> {
+    let encode = |v: f32| linear_to_srgb(v.clamp(0.0, 1.0));
+    srgb8_from_srgb(Srgb::new(encode(c.red), encode(c.green), encode(c.blue)))
+}
+
+fn srgb8_from_srgb(c: Srgb<f32>) -> Srgb<u8> {
+    let encode = |v: f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    Srgb::new(encode(c.red), encode(c.green), encode(c.blue))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPACES: [ColorSpace; 4] = [ColorSpace::Srgb, ColorSpace::LinearRgb, ColorSpace::Oklab, ColorSpace::Lab];
+
+    #[test]
+    fn test_roundtrip() {
+        let colors: Vec<Srgb<u8>> = (0..=255u8)
+            .step_by(5)
+            .flat_map(|v| [Srgb::new(v, 0, 255 - v), Srgb::new(v, v, v), Srgb::new(255, v, 17)])
+            .collect();
+        for space in SPACES {
+            assert_eq!(space.to_srgb8(&space.convert_srgb8(&colors)), colors, "{space:?}");
+        }
+    }
+
+    #[test]
+    fn test_palette() {
+        // Two well separated clusters give one palette color each, in every space.
+        let colors = [Srgb::new(200, 10, 10), Srgb::new(210, 20, 10), Srgb::new(10, 10, 200), Srgb::new(10, 20, 210)].repeat(16);
+        for space in SPACES {
+            for method in [QuantizeMethod::Wu, QuantizeMethod::kmeans()] {
+                let palette = space
+                    .palette(&space.convert_srgb8(&colors), &method, PaletteSize::try_from_u16(2).unwrap())
+                    .unwrap();
+                let mut palette = space.to_srgb8(&palette);
+                palette.sort_by_key(|c| c.red);
+                assert_eq!(palette.len(), 2, "{space:?}");
+                assert!(palette[0].blue > 190 && palette[1].red > 190, "{space:?} {palette:?}");
+            }
+        }
+    }
+}
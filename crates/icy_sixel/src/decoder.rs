@@ -1,6 +1,7 @@
 use crate::{
+    framing::unwrap_tmux,
     sixel_image::{BackgroundMode, PixelAspectRatio, SixelImage},
-    Result, SixelError, SIXEL_HEIGHT_LIMIT, SIXEL_PALETTE_MAX, SIXEL_WIDTH_LIMIT,
+    Result, SixelError, SIXEL_HEIGHT_LIMIT, SIXEL_PALETTE_MAX, SIXEL_PIXEL_LIMIT, SIXEL_WIDTH_LIMIT,
 };
 
 const SIXEL_CELL_HEIGHT: usize = 6;
@@ -14,7 +15,8 @@ use core::arch::x86::{__m128i, _mm_loadu_si128, _mm_storeu_si128};
 
 /// Internal decode function used by SixelImage::decode
 pub(crate) fn decode_sixel(data: &[u8]) -> Result<SixelImage> {
-    let parsed = AnsiPayload::parse(data)?;
+    let data = unwrap_tmux(data);
+    let parsed = AnsiPayload::parse(&data)?;
     let settings = DcsSettings::new(parsed.aspect_ratio, parsed.zero_color, parsed.grid_size);
     let payload = strip_string_terminator(parsed.payload);
     decode_sixel_from_dcs(payload, settings)
@@ -371,15 +373,8 @@ impl SixelDecoder {
         // P2=1 means transparent mode
         let transparent_mode = settings.zero_color == Some(1);
 
-        // In transparent mode, background has alpha=0; otherwise alpha=255
-        let background = if transparent_mode {
-            [0, 0, 0, 0] // Transparent
-        } else {
-            palette.rgb_bytes(background_index)
-        };
-
         let mut decoder = Self {
-            canvas: Canvas::new(background),
+            canvas: Canvas::new(),
             palette,
             color_index: 0,
             current_color,
@@ -605,9 +600,7 @@ impl SixelDecoder {
             return Err(SixelError::InvalidData("malformed SIXEL data".to_string()));
         }
         // Also guard against total pixel count to prevent memory exhaustion
-        // Max 256 MB of pixel data (64 million pixels * 4 bytes)
-        const MAX_PIXELS: usize = 64 * 1024 * 1024;
-        if width.saturating_mul(height) > MAX_PIXELS {
+        if width.saturating_mul(height) > SIXEL_PIXEL_LIMIT {
             return Err(SixelError::InvalidData("image dimensions too large".to_string()));
         }
         Ok(())
@@ -730,10 +723,15 @@ struct Canvas {
 }
 
 impl Canvas {
-    fn new(background: [u8; 4]) -> Self {
-        let mut data = vec![0u8; 4];
-        data[..4].copy_from_slice(&background);
-        Self { data, width: 1, height: 1 }
+    /// An empty canvas. Pixels are filled with the background when they
+    /// become visible, so a register 0 defined before the raster attributes
+    /// or the first sixel is used for all of them.
+    fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            width: 0,
+            height: 0,
+        }
     }
 
     fn ensure_visible(&mut self, width: usize, height: usize, background: [u8; 4]) -> Result<()> {
@@ -744,9 +742,8 @@ impl Canvas {
         let new_width = width.max(self.width);
         let new_height = height.max(self.height);
 
-        // Guard against memory exhaustion - max 256 MB of pixel data
-        const MAX_PIXELS: usize = 64 * 1024 * 1024;
-        if new_width.saturating_mul(new_height) > MAX_PIXELS {
+        // Guard against memory exhaustion
+        if new_width.saturating_mul(new_height) > SIXEL_PIXEL_LIMIT {
             return Err(SixelError::InvalidData("image dimensions too large".to_string()));
         }
 
@@ -884,7 +881,7 @@ fn pack_rgb(r: u8, g: u8, b: u8) -> u32 {
     ((r as u32) << 16) | ((g as u32) << 8) | b as u32
 }
 
-fn hls_to_rgb(h: i32, l: i32, s: i32) -> [u8; 3] {
+pub(crate) fn hls_to_rgb(h: i32, l: i32, s: i32) -> [u8; 3] {
     if s <= 0 {
         let gray = percent_to_byte(l);
         return [gray, gray, gray];
@@ -0,0 +1,587 @@
+//! Dithering algorithms applied on top of the quantized palette.
+//!
+//! quantette only ships Floyd-Steinberg. The remaining algorithms map the
+//! image onto the palette quantette produced, working on `[f32; 3]` colors in
+//! the configured [`ColorSpace`](crate::ColorSpace) (Oklab by default, the
+//! space quantette uses for its own dithering).
+
+use std::sync::OnceLock;
+
+use quantette::deps::palette::Srgb;
+
+/// Dithering algorithm used when mapping pixels onto the palette.
+///
+/// Error diffusion kernels spread the quantization error to neighboring pixels
+/// and work best for photographs. Ordered dithers (Bayer, blue noise) add a
+/// fixed, position-dependent threshold instead, which avoids "worm" artifacts,
+/// keeps flat areas stable and produces long identical runs that SIXEL's RLE
+/// compresses well - a good fit for UI screenshots and pixel art.
+///
+/// The strength of every algorithm is controlled by
+/// [`EncodeOptions::diffusion`](crate::EncodeOptions::diffusion).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dither {
+    /// No dithering, every pixel is mapped to its nearest palette color.
+    None,
+    /// Floyd-Steinberg error diffusion (default, provided by quantette).
+    #[default]
+    FloydSteinberg,
+    /// Atkinson error diffusion (diffuses only 3/4 of the error, high contrast).
+    Atkinson,
+    /// Jarvis-Judice-Ninke error diffusion (3 rows, smooth but slower).
+    JarvisJudiceNinke,
+    /// Stucki error diffusion (3 rows, sharper variant of Jarvis-Judice-Ninke).
+    Stucki,
+    /// Sierra (three-row) error diffusion.
+    Sierra,
+    /// Ordered dithering with a 2x2 Bayer matrix.
+    Bayer2x2,
+    /// Ordered dithering with a 4x4 Bayer matrix.
+    Bayer4x4,
+    /// Ordered dithering with an 8x8 Bayer matrix.
+    Bayer8x8,
+    /// Ordered dithering with a 32x32 blue noise threshold map.
+    BlueNoise,
+}
+
+impl Dither {
+    /// Error diffusion kernel as `(dx, dy, weight)` entries and divisor.
+    fn kernel(self) -> Option<(&'static [KernelEntry], f32)> {
+        const FLOYD_STEINBERG: &[KernelEntry] = &[(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)];
+        const ATKINSON: &[KernelEntry] = &[(1, 0, 1.0), (2, 0, 1.0), (-1, 1, 1.0), (0, 1, 1.0), (1, 1, 1.0), (0, 2, 1.0)];
+        const JARVIS_JUDICE_NINKE: &[KernelEntry] = &[
+            (1, 0, 7.0),
+            (2, 0, 5.0),
+            (-2, 1, 3.0),
+            (-1, 1, 5.0),
+            (0, 1, 7.0),
+            (1, 1, 5.0),
+            (2, 1, 3.0),
+            (-2, 2, 1.0),
+            (-1, 2, 3.0),
+            (0, 2, 5.0),
+            (1, 2, 3.0),
+            (2, 2, 1.0),
+        ];
+        const STUCKI: &[KernelEntry] = &[
+            (1, 0, 8.0),
+            (2, 0, 4.0),
+            (-2, 1, 2.0),
+            (-1, 1, 4.0),
+            (0, 1, 8.0),
+            (1, 1, 4.0),
+            (2, 1, 2.0),
+            (-2, 2, 1.0),
+            (-1, 2, 2.0),
+            (0, 2, 4.0),
+            (1, 2, 2.0),
+            (2, 2, 1.0),
+        ];
+        const SIERRA: &[KernelEntry] = &[
+            (1, 0, 5.0),
+            (2, 0, 3.0),
+            (-2, 1, 2.0),
+            (-1, 1, 4.0),
+            (0, 1, 5.0),
+            (1, 1, 4.0),
+            (2, 1, 2.0),
+            (-1, 2, 2.0),
+            (0, 2, 3.0),
+            (1, 2, 2.0),
+        ];
+
+        match self {
+            Self::FloydSteinberg => Some((FLOYD_STEINBERG, 16.0)),
+            Self::Atkinson => Some((ATKINSON, 8.0)),
+            Self::JarvisJudiceNinke => Some((JARVIS_JUDICE_NINKE, 48.0)),
+            Self::Stucki => Some((STUCKI, 42.0)),
+            Self::Sierra => Some((SIERRA, 32.0)),
+            _ => None,
+        }
+    }
+
+    /// Threshold map as `(size, thresholds)`; thresholds are in `-0.5..0.5`.
+    fn threshold_map(self) -> Option<(usize, &'static [f32])> {
+        static BAYER2: OnceLock<Vec<f32>> = OnceLock::new();
+        static BAYER4: OnceLock<Vec<f32>> = OnceLock::new();
+        static BAYER8: OnceLock<Vec<f32>> = OnceLock::new();
+        static BLUE_NOISE: OnceLock<Vec<f32>> = OnceLock::new();
+
+        match self {
+            Self::Bayer2x2 => Some((2, BAYER2.get_or_init(|| ranks_to_thresholds(&bayer_matrix(2))))),
+            Self::Bayer4x4 => Some((4, BAYER4.get_or_init(|| ranks_to_thresholds(&bayer_matrix(4))))),
+            Self::Bayer8x8 => Some((8, BAYER8.get_or_init(|| ranks_to_thresholds(&bayer_matrix(8))))),
+            Self::BlueNoise => Some((BLUE_NOISE_SIZE, BLUE_NOISE.get_or_init(|| ranks_to_thresholds(&blue_noise_matrix())))),
+            _ => None,
+        }
+    }
+}
+
+/// Threshold of an ordered dither at pixel `(x, y)`, in `-0.5..0.5`.
+///
+/// Returns `None` if `dither` is not an ordered dither.
+pub(crate) fn ordered_threshold(dither: Dither, x: usize, y: usize) -> Option<f32> {
+    dither.threshold_map().map(|(size, thresholds)| thresholds[(y % size) * size + (x % size)])
+}
+
+/// Error diffusion kernel entry: `(dx, dy, weight)`.
+type KernelEntry = (isize, usize, f32);
+
+/// Nearest palette color lookup.
+///
+/// The palette is sorted along the first component so a query only has to
+/// look at entries whose first component is within the current best distance.
+pub(crate) struct NearestColor {
+    sorted: Vec<([f32; 3], u8)>,
+}
+
+impl NearestColor {
+    pub(crate) fn new(palette: &[[f32; 3]]) -> Self {
+        let mut sorted: Vec<([f32; 3], u8)> = palette.iter().enumerate().map(|(i, c)| (*c, i as u8)).collect();
+        sorted.sort_by(|a, b| a.0[0].total_cmp(&b.0[0]));
+        Self { sorted }
+    }
+
+    /// Returns the palette index closest to `color` (squared euclidean distance).
+    pub(crate) fn index_of(&self, color: [f32; 3]) -> u8 {
+        let start = self.sorted.partition_point(|e| e.0[0] < color[0]);
+        let mut best = f32::INFINITY;
+        let mut best_index = 0u8;
+
+        let mut check = |entry: &([f32; 3], u8), best: &mut f32| -> bool {
+            let d0 = entry.0[0] - color[0];
+            if d0 * d0 >= *best {
+                return false;
+            }
+            let d1 = entry.0[1] - color[1];
+            let d2 = entry.0[2] - color[2];
+            let dist = d0 * d0 + d1 * d1 + d2 * d2;
+            if dist < *best {
+                *best = dist;
+                best_index = entry.1;
+            }
+            true
+        };
+
+        for entry in &self.sorted[start..] {
+            if !check(entry, &mut best) {
+                break;
+            }
+        }
+        for entry in self.sorted[..start].iter().rev() {
+            if !check(entry, &mut best) {
+                break;
+            }
+        }
+        best_index
+    }
+}
+
+/// Map `pixels` (row-major, `width` wide) onto `palette` using `dither`.
+///
+/// `strength` scales the diffused error for error diffusion kernels and the
+/// threshold amplitude for ordered dithers. `local_strength`, if given, scales
+/// it further per pixel (see [`adaptive_strength`]). `serpentine` alternates
+/// the scan direction per row for error diffusion.
+///
+/// Pixels for which `drawn` returns false are skipped: they get index 0,
+/// neither receive nor spread error, and so have no effect on visible pixels.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn dither_to_indices(
+    pixels: &[[f32; 3]],
+    width: usize,
+    palette: &[[f32; 3]],
+    dither: Dither,
+    strength: f32,
+    local_strength: Option<&[f32]>,
+    serpentine: bool,
+    drawn: impl Fn(usize) -> bool,
+) -> Vec<u8> {
+    let nearest = NearestColor::new(palette);
+    let strength = strength.clamp(0.0, 1.0);
+    let local = |i: usize| local_strength.map_or(1.0, |local| local[i]);
+
+    if strength > 0.0 {
+        if let Some((kernel, divisor)) = dither.kernel() {
+            return error_diffuse(pixels, width, palette, &nearest, kernel, strength / divisor, local, serpentine, drawn);
+        }
+        if let Some((size, thresholds)) = dither.threshold_map() {
+            return ordered(pixels, width, palette, &nearest, size, thresholds, strength, local, drawn);
+        }
+    }
+
+    pixels
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| if drawn(i) { nearest.index_of(c) } else { 0 })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn error_diffuse(
+    pixels: &[[f32; 3]],
+    width: usize,
+    palette: &[[f32; 3]],
+    nearest: &NearestColor,
+    kernel: &[KernelEntry],
+    scale: f32,
+    local: impl Fn(usize) -> f32,
+    serpentine: bool,
+    drawn: impl Fn(usize) -> bool,
+) -> Vec<u8> {
+    const PAD: usize = 2;
+    let rows = kernel.iter().map(|k| k.1).max().unwrap_or(0) + 1;
+    let stride = width + 2 * PAD;
+    // Ring buffer of error rows: row `r` of the ring holds the error for image row `y + r`.
+    let mut errors = vec![[0.0f32; 3]; rows * stride];
+    let mut indices = vec![0u8; pixels.len()];
+    let height = pixels.len() / width.max(1);
+
+    for y in 0..height {
+        let reverse = serpentine && y % 2 == 1;
+        let ring = |r: usize| ((y + r) % rows) * stride;
+        for step in 0..width {
+            let x = if reverse { width - 1 - step } else { step };
+            if !drawn(y * width + x) {
+                continue;
+            }
+            // Pixels at strength 0 are mapped as is and spread nothing.
+            let local = local(y * width + x);
+            let err = errors[ring(0) + x + PAD];
+            let src = pixels[y * width + x];
+            let color = [src[0] + err[0] * local, src[1] + err[1] * local, src[2] + err[2] * local];
+            let index = nearest.index_of(color);
+            indices[y * width + x] = index;
+            if local <= 0.0 {
+                continue;
+            }
+
+            let chosen = palette[index as usize];
+            let diff = [color[0] - chosen[0], color[1] - chosen[1], color[2] - chosen[2]];
+            for &(dx, dy, weight) in kernel {
+                let dx = if reverse { -dx } else { dx };
+                let target = (x + PAD) as isize + dx;
+                let cell = &mut errors[ring(dy) + target as usize];
+                let w = weight * scale * local;
+                cell[0] += diff[0] * w;
+                cell[1] += diff[1] * w;
+                cell[2] += diff[2] * w;
+            }
+        }
+        // The current row is consumed; it becomes the furthest row ahead.
+        errors[ring(0)..ring(0) + stride].fill([0.0; 3]);
+    }
+
+    indices
+}
+
+#[allow(clippy::too_many_arguments)]
+fn ordered(
+    pixels: &[[f32; 3]],
+    width: usize,
+    palette: &[[f32; 3]],
+    nearest: &NearestColor,
+    size: usize,
+    thresholds: &[f32],
+    strength: f32,
+    local: impl Fn(usize) -> f32,
+    drawn: impl Fn(usize) -> bool,
+) -> Vec<u8> {
+    // The threshold amplitude is roughly the distance between neighboring
+    // palette colors, assuming they are spread evenly over the color cube.
+    let spread = strength * palette_extent(palette) / (palette.len() as f32).cbrt().max(1.0);
+
+    pixels
+        .iter()
+        .enumerate()
+        .map(|(i, &c)| {
+            if !drawn(i) {
+                return 0;
+            }
+            let x = i % width;
+            let y = i / width;
+            let t = thresholds[(y % size) * size + (x % size)] * spread * local(i);
+            nearest.index_of([c[0] + t, c[1] + t, c[2] + t])
+        })
+        .collect()
+}
+
+/// Window radius for telling flat areas from gradients.
+const FLAT_RADIUS: usize = 3;
+/// Channel range within the window (in 8-bit steps) up to which an area
+/// counts as flat, and the range from which it is dithered fully.
+const FLAT_RANGE: (f32, f32) = (1.0, 5.0);
+/// Largest channel difference to a direct neighbor from which a pixel counts
+/// as an edge, and up to which it is dithered fully.
+const EDGE_STEP: (f32, f32) = (64.0, 32.0);
+
+/// Per-pixel dithering strength (0.0-1.0) for
+/// [`EncodeOptions::adaptive_diffusion`](crate::EncodeOptions::adaptive_diffusion).
+///
+/// Flat areas, where no channel varies by more than a step or two within
+/// [`FLAT_RADIUS`] pixels, and sharp edges like text, where a direct neighbor
+/// differs strongly, get 0. Smooth gradients and fine texture get 1.
+pub(crate) fn adaptive_strength(pixels: &[Srgb<u8>], width: usize) -> Vec<f32> {
+    let height = pixels.len() / width.max(1);
+    let channels = |c: Srgb<u8>| [c.red, c.green, c.blue];
+
+    // Per-channel minimum and maximum over the window, one axis at a time.
+    let window = |len: usize, i: usize| i.saturating_sub(FLAT_RADIUS)..(i + FLAT_RADIUS + 1).min(len);
+    let mut rows = vec![([u8::MAX; 3], [0u8; 3]); pixels.len()];
+    for y in 0..height {
+        let row = &pixels[y * width..(y + 1) * width];
+        for x in 0..width {
+            rows[y * width + x] = row[window(width, x)].iter().fold(([u8::MAX; 3], [0u8; 3]), |(lo, hi), &c| {
+                let c = channels(c);
+                (std::array::from_fn(|k| lo[k].min(c[k])), std::array::from_fn(|k| hi[k].max(c[k])))
+            });
+        }
+    }
+
+    let mut strength = vec![0.0f32; pixels.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let (lo, hi) = window(height, y).fold(([u8::MAX; 3], [0u8; 3]), |(lo, hi), wy| {
+                let (row_lo, row_hi) = rows[wy * width + x];
+                (std::array::from_fn(|k| lo[k].min(row_lo[k])), std::array::from_fn(|k| hi[k].max(row_hi[k])))
+            });
+            let range = (0..3).map(|k| hi[k] - lo[k]).max().unwrap_or(0) as f32;
+
+            let center = channels(pixels[y * width + x]);
+            let mut step = 0u8;
+            for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                    let neighbor = channels(pixels[ny * width + nx]);
+                    step = (0..3).map(|k| center[k].abs_diff(neighbor[k])).fold(step, u8::max);
+                }
+            }
+
+            let flat = ((range - FLAT_RANGE.0) / (FLAT_RANGE.1 - FLAT_RANGE.0)).clamp(0.0, 1.0);
+            let edge = ((EDGE_STEP.0 - step as f32) / (EDGE_STEP.0 - EDGE_STEP.1)).clamp(0.0, 1.0);
+            strength[y * width + x] = flat * edge;
+        }
+    }
+    strength
+}
+
+/// Largest per-component range covered by the palette.
+fn palette_extent(palette: &[[f32; 3]]) -> f32 {
+    (0..3)
+        .map(|c| {
+            let (min, max) = palette
+                .iter()
+                .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), p| (lo.min(p[c]), hi.max(p[c])));
+            max - min
+        })
+        .fold(0.0, f32::max)
+}
+
+fn ranks_to_thresholds(ranks: &[u32]) -> Vec<f32> {
+    let n = ranks.len() as f32;
+    ranks.iter().map(|&r| (r as f32 + 0.5) / n - 0.5).collect()
+}
+
+/// Recursively built Bayer index matrix of `size` x `size` (size is a power of two).
+fn bayer_matrix(size: usize) -> Vec<u32> {
+    let mut matrix = vec![0u32];
+    let mut n = 1;
+    while n < size {
+        let mut next = vec![0u32; 4 * n * n];
+        for y in 0..n {
+            for x in 0..n {
+                let v = 4 * matrix[y * n + x];
+                next[y * 2 * n + x] = v;
+                next[y * 2 * n + x + n] = v + 2;
+                next[(y + n) * 2 * n + x] = v + 3;
+                next[(y + n) * 2 * n + x + n] = v + 1;
+            }
+        }
+        matrix = next;
+        n *= 2;
+    }
+    matrix
+}
+
+const BLUE_NOISE_SIZE: usize = 32;
+
+/// Blue noise rank matrix generated with Ulichney's void-and-cluster method.
+fn blue_noise_matrix() -> Vec<u32> {
+    const N: usize = BLUE_NOISE_SIZE;
+    const LEN: usize = N * N;
+    const SIGMA: f32 = 1.5;
+
+    // Gaussian weights by toroidal offset.
+    let mut gauss = vec![0.0f32; LEN];
+    for dy in 0..N {
+        for dx in 0..N {
+            let wx = dx.min(N - dx) as f32;
+            let wy = dy.min(N - dy) as f32;
+            gauss[dy * N + dx] = (-(wx * wx + wy * wy) / (2.0 * SIGMA * SIGMA)).exp();
+        }
+    }
+
+    struct Pattern<'a> {
+        bits: Vec<bool>,
+        energy: Vec<f32>,
+        gauss: &'a [f32],
+    }
+
+    impl Pattern<'_> {
+        fn toggle(&mut self, p: usize, on: bool) {
+            self.bits[p] = on;
+            let sign = if on { 1.0 } else { -1.0 };
+            let (px, py) = (p % N, p / N);
+            for q in 0..LEN {
+                let dx = (q % N + N - px) % N;
+                let dy = (q / N + N - py) % N;
+                self.energy[q] += sign * self.gauss[dy * N + dx];
+            }
+        }
+
+        fn tightest_cluster(&self) -> usize {
+            (0..LEN)
+                .filter(|&p| self.bits[p])
+                .max_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+                .unwrap_or(0)
+        }
+
+        fn largest_void(&self) -> usize {
+            (0..LEN)
+                .filter(|&p| !self.bits[p])
+                .min_by(|&a, &b| self.energy[a].total_cmp(&self.energy[b]))
+                .unwrap_or(0)
+        }
+    }
+
+    // Deterministic initial pattern (~10% ones) from a fixed-seed xorshift.
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    let mut pattern = Pattern {
+        bits: vec![false; LEN],
+        energy: vec![0.0; LEN],
+        gauss: &gauss,
+    };
+    let mut ones = 0;
+    while ones < LEN / 10 {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        let p = (state % LEN as u64) as usize;
+        if !pattern.bits[p] {
+            pattern.toggle(p, true);
+            ones += 1;
+        }
+    }
+
+    // Relax the initial pattern until moving the tightest cluster into the
+    // largest void does not change anything.
+    for _ in 0..LEN {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster, false);
+        let void = pattern.largest_void();
+        pattern.toggle(void, true);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0u32; LEN];
+
+    // Phase 1: rank the initial ones by repeatedly removing the tightest cluster.
+    let mut phase1 = Pattern {
+        bits: pattern.bits.clone(),
+        energy: pattern.energy.clone(),
+        gauss: &gauss,
+    };
+    let mut rank = ones;
+    while rank > 0 {
+        let cluster = phase1.tightest_cluster();
+        phase1.toggle(cluster, false);
+        rank -= 1;
+        ranks[cluster] = rank as u32;
+    }
+
+    // Phases 2 and 3: fill the largest voids until the pattern is full.
+    let mut rank = ones;
+    while rank < LEN {
+        let void = pattern.largest_void();
+        pattern.toggle(void, true);
+        ranks[void] = rank as u32;
+        rank += 1;
+    }
+
+    ranks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_permutation(ranks: &[u32]) -> bool {
+        let mut sorted = ranks.to_vec();
+        sorted.sort_unstable();
+        sorted.iter().enumerate().all(|(i, &r)| r == i as u32)
+    }
+
+    #[test]
+    fn test_threshold_maps_are_permutations() {
+        assert_eq!(bayer_matrix(2), vec![0, 2, 3, 1]);
+        assert!(is_permutation(&bayer_matrix(8)));
+        assert!(is_permutation(&blue_noise_matrix()));
+    }
+
+    #[test]
+    fn test_nearest_color() {
+        let palette = [[0.0, 0.0, 0.0], [1.0, 1.0, 1.0], [0.5, 0.0, 0.0], [0.5, 1.0, 0.0]];
+        let nearest = NearestColor::new(&palette);
+        assert_eq!(nearest.index_of([0.1, 0.1, 0.1]), 0);
+        assert_eq!(nearest.index_of([0.9, 0.8, 0.9]), 1);
+        assert_eq!(nearest.index_of([0.5, 0.1, 0.0]), 2);
+        assert_eq!(nearest.index_of([0.45, 0.9, 0.1]), 3);
+    }
+
+    #[test]
+    fn test_dither_mixes_gray_between_black_and_white() {
+        let palette = [[0.0, 0.0, 0.0], [1.0, 1.0, 1.0]];
+        let pixels = vec![[0.5, 0.5, 0.5]; 16 * 16];
+        for dither in [Dither::FloydSteinberg, Dither::Atkinson, Dither::Stucki, Dither::Bayer4x4, Dither::BlueNoise] {
+            let indices = dither_to_indices(&pixels, 16, &palette, dither, 1.0, None, true, |_| true);
+            let white = indices.iter().filter(|&&i| i == 1).count();
+            assert!((64..=192).contains(&white), "{dither:?} produced {white} white pixels");
+        }
+        let indices = dither_to_indices(&pixels, 16, &palette, Dither::None, 1.0, None, true, |_| true);
+        assert!(indices.iter().all(|&i| i == indices[0]));
+    }
+
+    #[test]
+    fn test_adaptive_strength() {
+        // A flat area, black and white strokes and a smooth gradient side by side.
+        let (width, height) = (48, 16);
+        let pixels: Vec<Srgb<u8>> = (0..width * height)
+            .map(|i| match i % width {
+                0..16 => Srgb::new(100, 150, 200),
+                x @ 16..32 => {
+                    let v = if x % 4 < 2 { 0 } else { 255 };
+                    Srgb::new(v, v, v)
+                }
+                x => {
+                    let v = (x * 3) as u8;
+                    Srgb::new(v, v, v)
+                }
+            })
+            .collect();
+        let strength = adaptive_strength(&pixels, width);
+        let at = |x: usize| strength[8 * width + x];
+        assert_eq!(at(8), 0.0, "flat");
+        assert_eq!(at(24), 0.0, "edge");
+        assert_eq!(at(40), 1.0, "gradient");
+    }
+
+    #[test]
+    fn test_undrawn_pixels_do_not_spread_error() {
+        let palette = [[0.0, 0.0, 0.0], [1.0, 1.0, 1.0]];
+        // Left half is invisible white, right half visible black.
+        let pixels: Vec<[f32; 3]> = (0..64).map(|i| if i % 8 < 4 { [1.0; 3] } else { [0.0; 3] }).collect();
+        let indices = dither_to_indices(&pixels, 8, &palette, Dither::FloydSteinberg, 1.0, None, true, |i| i % 8 >= 4);
+        assert!(indices.iter().all(|&i| i == 0));
+    }
+}
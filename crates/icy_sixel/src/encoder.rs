@@ -3,57 +3,305 @@
 //! This encoder uses the quantette library (MIT/Apache licensed) for optimal
 //! color palette generation and dithering, then encodes the result to SIXEL format.
 
-use crate::{BackgroundMode, PixelAspectRatio, Result, SixelError};
+use crate::{
+    band_simd::{run_length, same_color_bits, spread_bits},
+    color_space::ColorSpace,
+    dither::{adaptive_strength, dither_to_indices, ordered_threshold, Dither},
+    framing::OutputFraming,
+    grayscale::{luminance, quantize_gray, GrayLevels},
+    high_color::{band_palettes, Redefinition},
+    progress::{EncodeStage, ProgressHook},
+    resize::{resize_rgba, Resize, ResizeFilter},
+    BackgroundMode, PixelAspectRatio, Result, SixelError, SIXEL_HEIGHT_LIMIT, SIXEL_PIXEL_LIMIT, SIXEL_WIDTH_LIMIT,
+};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
 use quantette::{deps::palette::Srgb, dither::FloydSteinberg, ImageRef, PaletteSize, Pipeline};
 
-// Re-export QuantizeMethod for public API
-pub use quantette::QuantizeMethod;
+// Re-export QuantizeMethod and the k-means settings for public API
+pub use quantette::{kmeans::KmeansOptions, QuantizeMethod};
+
+/// An 8-bit RGB color.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Rgb {
+    /// Red component
+    pub r: u8,
+    /// Green component
+    pub g: u8,
+    /// Blue component
+    pub b: u8,
+}
+
+impl Rgb {
+    /// Creates a color from its red, green and blue components.
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+}
+
+/// How the alpha channel is reduced to SIXEL's 1-bit transparency.
+///
+/// SIXEL pixels are either drawn or left undrawn (with P2=1 the terminal
+/// content shows through), so partially transparent pixels have to be
+/// mapped onto one of the two.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AlphaMode {
+    /// Pixels with `alpha >= threshold` are drawn, all others are left undrawn.
+    ///
+    /// The default is `Threshold(128)`.
+    Threshold(u8),
+    /// Dither alpha into the mask with an ordered 8x8 Bayer matrix.
+    /// Soft edges become a stable stipple pattern.
+    OrderedDither,
+    /// Dither alpha into the mask with Floyd-Steinberg error diffusion.
+    DiffusionDither,
+    /// Blend partially transparent pixels over a known background color
+    /// (usually the terminal background) before quantizing. Only fully
+    /// transparent pixels (alpha = 0) are left undrawn.
+    Composite(Rgb),
+}
+
+impl Default for AlphaMode {
+    fn default() -> Self {
+        Self::Threshold(128)
+    }
+}
+
+/// How palette entries are defined in the SIXEL stream.
+///
+/// Both formats have a limited precision (percent steps, whole degrees), so
+/// palette colors that would be sent identically are merged into one register.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PaletteFormat {
+    /// `#n;2;r;g;b` with red, green and blue in percent (default).
+    #[default]
+    Rgb,
+    /// `#n;1;h;l;s` with hue in degrees and lightness and saturation in
+    /// percent. Some terminals render HLS definitions more accurately.
+    Hls,
+}
+
+/// Which colors the encoder produces.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Full color with a quantized palette (default).
+    #[default]
+    Color,
+    /// 1-bit output for monochrome terminals and printers.
+    ///
+    /// The image is thresholded or dithered (see [`EncodeOptions::dither`])
+    /// by luminance and sent as a single data plane per band without any
+    /// color definitions, so set bits are drawn in the device's foreground
+    /// color. By default light pixels are set, which suits terminals with a
+    /// light foreground. Printers put ink on set bits and want `invert: true`.
+    Monochrome {
+        /// Set bits for dark pixels instead of light ones.
+        invert: bool,
+    },
+    /// Grayscale output: pixels are reduced to their luminance and mapped
+    /// onto gray levels, skipping color quantization. `max_colors` is not
+    /// used, the level count is part of [`GrayLevels`].
+    Grayscale(GrayLevels),
+    /// More than 256 colors: each group of `bands_per_palette` bands (6 pixel
+    /// rows each) gets its own palette of up to `max_colors` colors, and
+    /// color registers are redefined between bands as needed.
+    ///
+    /// Registers that already hold a color of the next palette are reused,
+    /// so only the colors that change are sent again. Error diffusion is
+    /// done per group and doesn't cross group boundaries.
+    ///
+    /// This needs a terminal that keeps pixels in the color they were drawn
+    /// with; terminals that repaint the whole image when a register changes
+    /// (like the VT340) show earlier bands in the wrong colors.
+    /// [`SixelImage::encode_high_color`](crate::SixelImage::encode_high_color)
+    /// reports the size compared with a single palette.
+    HighColor {
+        /// Number of bands sharing a palette (at least 1). Fewer bands per
+        /// palette give more colors and a longer stream.
+        bands_per_palette: usize,
+    },
+}
+
+/// Framing for DEC-style sixel printers (LA50, LN03, ...).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrinterOptions {
+    /// Maximum image width in printer pixels. Wider images are shrunk to fit
+    /// (default: 800).
+    pub page_width: usize,
+    /// Send a form feed after the image to eject the page (default: `true`).
+    pub form_feed: bool,
+}
+
+impl Default for PrinterOptions {
+    fn default() -> Self {
+        Self {
+            page_width: 800,
+            form_feed: true,
+        }
+    }
+}
+
+/// RGBA pixels of a `width` x `height` rectangle inside a possibly larger
+/// buffer, so that regions can be encoded without copying them out first.
+#[derive(Clone, Copy)]
+pub(crate) struct RgbaView<'a> {
+    data: &'a [u8],
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    /// Bytes from the start of one row to the start of the next.
+    stride: usize,
+}
+
+impl<'a> RgbaView<'a> {
+    /// View of the `width` x `height` rectangle at (`x`, `y`) of a buffer
+    /// holding `buffer_width` x `buffer_height` pixels.
+    pub(crate) fn region(rgba: &'a [u8], buffer_width: usize, buffer_height: usize, x: usize, y: usize, width: usize, height: usize) -> Result<Self> {
+        let fits = |start: usize, len: usize, limit: usize| len > 0 && start.checked_add(len).is_some_and(|end| end <= limit);
+        if !fits(x, width, buffer_width) || !fits(y, height, buffer_height) {
+            return Err(SixelError::InvalidRegion { x, y, width, height });
+        }
+        let expected = buffer_width * buffer_height * 4;
+        if rgba.len() != expected {
+            return Err(SixelError::BufferSizeMismatch { expected, actual: rgba.len() });
+        }
+        Ok(Self {
+            data: &rgba[(y * buffer_width + x) * 4..],
+            width,
+            height,
+            stride: buffer_width * 4,
+        })
+    }
+
+    /// View of a whole `width` x `height` image.
+    pub(crate) fn whole(rgba: &'a [u8], width: usize, height: usize) -> Result<Self> {
+        if width == 0 || height == 0 {
+            return Err(SixelError::InvalidDimensions { width, height });
+        }
+        let expected = width * height * 4;
+        if rgba.len() != expected {
+            return Err(SixelError::BufferSizeMismatch { expected, actual: rgba.len() });
+        }
+        Self::region(rgba, width, height, 0, 0, width, height)
+    }
+
+    /// The rows of the rectangle, `width * 4` bytes each.
+    pub(crate) fn rows(self) -> impl Iterator<Item = &'a [u8]> {
+        (0..self.height).map(move |y| &self.data[y * self.stride..][..self.width * 4])
+    }
+
+    /// Row `y` of the rectangle, `width * 4` bytes.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn row(self, y: usize) -> &'a [u8] {
+        &self.data[y * self.stride..][..self.width * 4]
+    }
+
+    /// Pixel `i` of the rectangle in row-major order, 4 bytes.
+    #[cfg(feature = "rayon")]
+    pub(crate) fn pixel(self, i: usize) -> &'a [u8] {
+        &self.data[(i / self.width) * self.stride + (i % self.width) * 4..][..4]
+    }
 
-/// Color type for palette entries (RGB).
-#[derive(Clone, Copy, Debug)]
-struct Rgb {
-    r: u8,
-    g: u8,
-    b: u8,
+    /// The pixels of the rectangle in row-major order, 4 bytes each.
+    pub(crate) fn pixels(self) -> impl Iterator<Item = &'a [u8]> {
+        self.rows().flat_map(|row| row.chunks_exact(4))
+    }
 }
 
 /// A compact 1-bit-per-element mask backed by `u64` words.
 ///
 /// Uses 1/8 the memory of a `Vec<bool>`, which improves cache behavior in the
 /// per-color band-encoding loop that re-reads the opacity mask many times.
-struct BitMask {
+#[derive(Debug, Default)]
+pub(crate) struct BitMask {
     words: Vec<u64>,
 }
 
 impl BitMask {
     /// Create a mask with `len` bits, all cleared.
-    fn zeros(len: usize) -> Self {
+    pub(crate) fn zeros(len: usize) -> Self {
         Self {
             words: vec![0; len.div_ceil(64)],
         }
     }
 
+    /// Resize the mask to `len` bits and clear them all, keeping the allocation.
+    fn reset(&mut self, len: usize) {
+        self.words.clear();
+        self.words.resize(len.div_ceil(64), 0);
+    }
+
     /// Set the bit at `index` to 1.
     #[inline]
-    fn set(&mut self, index: usize) {
+    pub(crate) fn set(&mut self, index: usize) {
         self.words[index >> 6] |= 1u64 << (index & 63);
     }
 
+    /// Set the bit at `index` to 0.
+    #[inline]
+    fn clear(&mut self, index: usize) {
+        self.words[index >> 6] &= !(1u64 << (index & 63));
+    }
+
     /// Return whether the bit at `index` is set.
     #[inline]
-    fn get(&self, index: usize) -> bool {
+    pub(crate) fn get(&self, index: usize) -> bool {
         (self.words[index >> 6] >> (index & 63)) & 1 != 0
     }
+
+    /// Return the 64 bits starting at `index`; bits past the end are 0.
+    #[inline]
+    fn word_at(&self, index: usize) -> u64 {
+        let (word, shift) = (index >> 6, index & 63);
+        let low = self.words.get(word).map_or(0, |&w| w >> shift);
+        let high = match shift {
+            0 => 0,
+            _ => self.words.get(word + 1).map_or(0, |&w| w << (64 - shift)),
+        };
+        low | high
+    }
+
+    /// Return the number of set bits.
+    fn count_ones(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
 }
 
 /// Options for the quantette-based SIXEL encoder.
+///
+/// Options are added in minor releases, so the struct can't be built with a
+/// literal outside this crate; start from [`EncodeOptions::default()`] and
+/// set the fields that differ.
 #[derive(Clone, Debug)]
+#[non_exhaustive]
 pub struct EncodeOptions {
     /// Maximum number of colors in the palette (2-256).
     /// Fewer colors = smaller SIXEL output but less accurate colors.
     pub max_colors: u16,
 
-    /// Floyd-Steinberg error diffusion strength (0.0-1.0).
+    /// Colors that are always part of the palette (default: empty).
+    ///
+    /// Brand colors or pure black and white for text come out exactly; the
+    /// remaining `max_colors - fixed_colors.len()` entries are picked from the
+    /// image as usual and dithering maps onto both. Colors beyond
+    /// `max_colors` are ignored. Applies to [`ColorMode::Color`] and
+    /// [`ColorMode::HighColor`], where every band palette keeps them.
+    pub fixed_colors: Vec<Rgb>,
+
+    /// Per-pixel importance for picking the palette (default: `None`).
+    ///
+    /// One weight per pixel of the encoded image or region, row by row,
+    /// before resizing. Pixels count in proportion to their weight, so
+    /// heavily weighted regions such as the active dialog or faces get more
+    /// palette entries; weight 0 pixels are still mapped but don't influence
+    /// the palette. Applies to [`ColorMode::Color`] and
+    /// [`ColorMode::HighColor`].
+    pub weight_map: Option<Arc<[u8]>>,
+
+    /// Dithering strength (0.0-1.0).
     ///
     /// Controls how much quantization error is spread to neighboring pixels:
     /// - **0.875**: Default (7/8), best for photographs with smooth gradients
@@ -62,9 +310,58 @@ pub struct EncodeOptions {
     ///
     /// Higher values produce smoother gradients but may introduce noise.
     /// Lower values preserve sharp edges but may show color banding.
-    /// Values are clamped to the range 0.0-1.0.
+    /// For ordered dithers ([`Dither::Bayer4x4`] etc.) this scales the
+    /// threshold amplitude instead. Values are clamped to the range 0.0-1.0.
     pub diffusion: f32,
 
+    /// Dithering algorithm.
+    ///
+    /// [`Dither::FloydSteinberg`] (default) is handled by quantette; the other
+    /// algorithms are applied to the palette quantette computes.
+    pub dither: Dither,
+
+    /// Vary the dithering strength with the image content (default: `false`).
+    ///
+    /// Flat areas and sharp edges such as text are mapped without dithering,
+    /// smooth gradients and texture get the full [`diffusion`](Self::diffusion).
+    /// Screenshots that mix text with photos come out without speckles
+    /// around the text and without banding in the photos, and the flat areas
+    /// compress better. Flatness is judged over a 7x7 window and edges by the
+    /// difference to the direct neighbors.
+    pub adaptive_diffusion: bool,
+
+    /// Alternate the scan direction on every row for error diffusion
+    /// (default: `true`). Serpentine scanning avoids the diagonal artifacts
+    /// of left-to-right only diffusion.
+    pub serpentine: bool,
+
+    /// How alpha is turned into the drawn/undrawn pixel mask.
+    pub alpha: AlphaMode,
+
+    /// Color key and tolerance for images without an alpha channel
+    /// (default: `None`).
+    ///
+    /// Pixels whose red, green and blue each differ from the key by at most
+    /// the tolerance are left undrawn, like fully transparent ones, e.g. the
+    /// magenta of a sprite sheet or the transparent index of a GIF frame.
+    /// They are keyed out before resizing, so the key color doesn't bleed
+    /// into the edges. Use it with [`BackgroundMode::Transparent`] (P2=1).
+    pub transparent_color: Option<(Rgb, u8)>,
+
+    /// Background color to leave undrawn (default: `None`).
+    ///
+    /// Pixels sent as this color are not written at all. Color register 0
+    /// is defined as the background and the image is sent in opaque mode
+    /// (P2=0) with its full size in the raster attributes, so the terminal
+    /// fills the undrawn pixels with register 0. Large flat backgrounds of
+    /// documents, screenshots and charts then cost nothing. The register
+    /// counts against [`max_colors`](Self::max_colors).
+    ///
+    /// Only applies to [`ColorMode::Color`] and [`ColorMode::HighColor`].
+    /// Terminals that fill opaque images with their own background instead
+    /// of register 0 show that color in its place.
+    pub background_color: Option<Rgb>,
+
     /// Color quantization method.
     ///
     /// Available methods:
@@ -72,15 +369,106 @@ pub struct EncodeOptions {
     /// - [`QuantizeMethod::Kmeans`]: K-means clustering (slower but may be more accurate)
     ///
     /// For most use cases, Wu's method provides excellent results.
+    ///
+    /// K-means is tuned with [`KmeansOptions`]. It refines the Wu palette one
+    /// sampled pixel at a time, so the number of samples is its iteration
+    /// count: `sampling_factor` sets it relative to the image size (1.0 by
+    /// default), capped at `max_samples` (262144 by default). Sampling less
+    /// makes large images faster. The samples are drawn from a generator
+    /// seeded with `seed` (0 by default), so the output is reproducible across
+    /// runs and machines; `batch_size` only applies to parallel k-means.
+    ///
+    /// ```rust
+    /// use icy_sixel::{EncodeOptions, KmeansOptions, QuantizeMethod};
+    ///
+    /// let mut opts = EncodeOptions::default();
+    /// opts.quantize_method = QuantizeMethod::Kmeans(KmeansOptions::new().sampling_factor(0.25).seed(42));
+    /// ```
     pub quantize_method: QuantizeMethod,
+
+    /// Color space in which the palette is computed and dither error is
+    /// spread (default: [`ColorSpace::Oklab`]).
+    ///
+    /// Gamma encoded sRGB is the cheapest but shifts hues and darkens
+    /// dithered gradients, most visibly at 16 colors or fewer. Only applies
+    /// to [`ColorMode::Color`]; the gray modes always work on luminance.
+    pub color_space: ColorSpace,
+
+    /// Spend extra effort on making the SIXEL stream as short as possible
+    /// (default: `false`).
+    ///
+    /// Trailing empty sixels of a color row, the carriage return after the
+    /// last color of a band and the final graphics new line are dropped, and
+    /// colors are ordered within each band so that the color register stays
+    /// selected across band boundaries where possible. The image size is
+    /// declared in the raster attributes instead, so undrawn margins at the
+    /// right and bottom are kept and the decoded image is identical; an
+    /// undrawn bottom band is even kept where the plain output loses it.
+    pub optimize_size: bool,
+
+    /// Color format of the palette definitions (default: [`PaletteFormat::Rgb`]).
+    ///
+    /// Palette colors that are sent identically in this format share one
+    /// register. With [`QuantizeMethod::Wu`] the image is then quantized and
+    /// dithered a second time with a palette larger by the freed registers,
+    /// which roughly doubles the quantization cost of such images.
+    pub palette_format: PaletteFormat,
+
+    /// Resize the image before encoding (default: `None`, encode as is).
+    ///
+    /// Resizing takes the [`PixelAspectRatio`] into account, see [`Resize`].
+    pub resize: Option<Resize>,
+
+    /// Resampling filter used by [`EncodeOptions::resize`]
+    /// (default: [`ResizeFilter::Lanczos3`]).
+    pub resize_filter: ResizeFilter,
+
+    /// Color or monochrome output (default: [`ColorMode::Color`]).
+    pub color_mode: ColorMode,
+
+    /// Frame the output for a sixel printer (default: `None`).
+    ///
+    /// Printers have no square pixel mode, so square images are sent with a
+    /// 2:1 pixel aspect ratio and pre-scaled vertically to match.
+    pub printer: Option<PrinterOptions>,
+
+    /// Transport framing: multiplexer passthrough, 8-bit C1 controls and
+    /// line wrapping (default: a plain 7-bit sequence on one line).
+    pub framing: OutputFraming,
+
+    /// Progress callback and cancellation flag (default: none).
+    ///
+    /// The hook is called between resizing, quantization and dithering and
+    /// before every band; once its flag is set the encode stops with
+    /// [`SixelError::Cancelled`]. Searches that encode several times, like
+    /// [`SixelImage::encode_to_budget`](crate::SixelImage::encode_to_budget),
+    /// report every attempt from the start.
+    pub progress: ProgressHook,
 }
 
 impl Default for EncodeOptions {
     fn default() -> Self {
         Self {
             max_colors: 256,
+            fixed_colors: Vec::new(),
+            weight_map: None,
             diffusion: FloydSteinberg::DEFAULT_ERROR_DIFFUSION,
             quantize_method: QuantizeMethod::Wu,
+            color_space: ColorSpace::default(),
+            dither: Dither::default(),
+            adaptive_diffusion: false,
+            serpentine: true,
+            alpha: AlphaMode::default(),
+            transparent_color: None,
+            background_color: None,
+            optimize_size: false,
+            palette_format: PaletteFormat::default(),
+            resize: None,
+            resize_filter: ResizeFilter::default(),
+            color_mode: ColorMode::default(),
+            printer: None,
+            framing: OutputFraming::default(),
+            progress: ProgressHook::default(),
         }
     }
 }
@@ -111,6 +499,67 @@ pub fn sixel_encode(rgba: &[u8], width: usize, height: usize, opts: &EncodeOptio
     sixel_encode_impl(rgba, width, height, opts, PixelAspectRatio::default(), BackgroundMode::default())
 }
 
+/// A SIXEL encoder that keeps its scratch buffers between calls.
+///
+/// [`SixelImage::encode_with`](crate::SixelImage::encode_with) allocates the
+/// pixel copy, the opacity mask, the band buffers and the output string for
+/// every image. An `Encoder` keeps them instead, so encoding a stream of
+/// frames of the same or a smaller size, e.g. an animation or a live
+/// preview, doesn't allocate them again. The output is the same as that of
+/// `encode_with`. With the `rayon` feature, large images are written through
+/// per-band buffers that are not kept.
+///
+/// # Example
+/// ```rust
+/// use icy_sixel::{EncodeOptions, Encoder, SixelImage};
+///
+/// let mut opts = EncodeOptions::default();
+/// opts.max_colors = 16;
+/// let mut encoder = Encoder::new(opts);
+/// for frame in 0..3u8 {
+///     let image = SixelImage::from_rgba(vec![frame * 80; 32 * 32 * 4], 32, 32);
+///     let sixel: &str = encoder.encode(&image)?;
+///     print!("{sixel}");
+/// }
+/// # Ok::<(), icy_sixel::SixelError>(())
+/// ```
+#[derive(Debug, Default)]
+pub struct Encoder {
+    options: EncodeOptions,
+    buffers: EncodeBuffers,
+}
+
+impl Encoder {
+    /// Creates an encoder using `options` for every image.
+    pub fn new(options: EncodeOptions) -> Self {
+        Self {
+            options,
+            buffers: EncodeBuffers::default(),
+        }
+    }
+
+    /// The options images are encoded with.
+    pub fn options(&self) -> &EncodeOptions {
+        &self.options
+    }
+
+    /// Mutable access to the options, e.g. to change them between frames.
+    pub fn options_mut(&mut self) -> &mut EncodeOptions {
+        &mut self.options
+    }
+
+    /// Encodes `image` to a SIXEL string.
+    ///
+    /// The returned string lives in the encoder and is overwritten by the
+    /// next call; copy it with `to_owned()` to keep it.
+    pub fn encode(&mut self, image: &crate::SixelImage) -> Result<&str> {
+        let view = RgbaView::whole(&image.pixels, image.width, image.height)?;
+        let sixel = encode_view_with_buffers(view, &self.options, image.aspect_ratio, image.background_mode, &mut self.buffers)?;
+        self.buffers.band.out = sixel;
+        Ok(&self.buffers.band.out)
+    }
+}
+
 pub(crate) fn sixel_encode_impl(
     rgba: &[u8],
     width: usize,
@@ -119,63 +568,730 @@ pub(crate) fn sixel_encode_impl(
     pixel_aspect_ratio: PixelAspectRatio,
     background_mode: BackgroundMode,
 ) -> Result<String> {
-    if width == 0 || height == 0 {
-        return Err(SixelError::InvalidDimensions { width, height });
-    }
-    let expected = width * height * 4;
-    if rgba.len() != expected {
-        return Err(SixelError::BufferSizeMismatch { expected, actual: rgba.len() });
-    }
-
-    // Single pass over the RGBA buffer building both the transparency mask
-    // (set bit = opaque) and the Srgb<u8> pixels used for quantization
-    // (quantette uses palette crate types).
-    let pixel_count = width * height;
-    let mut opacity_mask = BitMask::zeros(pixel_count);
-    let mut rgb_pixels: Vec<Srgb<u8>> = Vec::with_capacity(pixel_count);
-    for (i, c) in rgba.chunks_exact(4).enumerate() {
-        if c[3] >= 128 {
-            opacity_mask.set(i);
+    sixel_encode_view(RgbaView::whole(rgba, width, height)?, opts, pixel_aspect_ratio, background_mode)
+}
+
+/// Encode the pixels of `view`, which may be a region of a larger image.
+pub(crate) fn sixel_encode_view(
+    view: RgbaView<'_>,
+    opts: &EncodeOptions,
+    pixel_aspect_ratio: PixelAspectRatio,
+    background_mode: BackgroundMode,
+) -> Result<String> {
+    encode_view_with_buffers(view, opts, pixel_aspect_ratio, background_mode, &mut EncodeBuffers::default())
+}
+
+/// Scratch buffers of the encoding pipeline. [`Encoder`] keeps them between
+/// frames; they only grow, so frames of the same or a smaller size don't
+/// allocate them again.
+#[derive(Debug, Default)]
+struct EncodeBuffers {
+    opacity_mask: BitMask,
+    rgb_pixels: Vec<Srgb<u8>>,
+    band: BandBuffers,
+}
+
+/// Scratch buffers of [`encode_indexed_to_sixel`].
+#[derive(Debug, Default)]
+struct BandBuffers {
+    /// The 6-bit sixel value for every (color, column) pair of a band.
+    sixels: Vec<u8>,
+    colors_used: Vec<bool>,
+    /// Drawn and same-color sixel bits of every column, see [`BandSource::scatter`].
+    columns: Vec<u8>,
+    order: Vec<usize>,
+    next_band_stamp: Vec<usize>,
+    /// Output of the previous encode, reused for the next one.
+    out: String,
+}
+
+fn encode_view_with_buffers(
+    view: RgbaView<'_>,
+    opts: &EncodeOptions,
+    pixel_aspect_ratio: PixelAspectRatio,
+    background_mode: BackgroundMode,
+    buffers: &mut EncodeBuffers,
+) -> Result<String> {
+    let (width, height) = (view.width, view.height);
+
+    let pixel_aspect_ratio = match opts.printer {
+        Some(_) if pixel_aspect_ratio.is_square() => PixelAspectRatio::Ratio2To1,
+        _ => pixel_aspect_ratio,
+    };
+    let mut target_size = opts.resize.map(|resize| resize.target_size(width, height, pixel_aspect_ratio));
+    if let Some(printer) = opts.printer {
+        let (new_width, new_height) = target_size.unwrap_or_else(|| Resize::Scale(1).target_size(width, height, pixel_aspect_ratio));
+        let page_width = printer.page_width.max(1);
+        target_size = Some(if new_width > page_width {
+            (page_width, (new_height.saturating_mul(page_width) / new_width).max(1))
+        } else {
+            (new_width, new_height)
+        });
+    }
+    // Don't let a large scale factor or target size allocate without bound.
+    if let Some((new_width, new_height)) = target_size {
+        if new_width > SIXEL_WIDTH_LIMIT || new_height > SIXEL_HEIGHT_LIMIT || new_width.saturating_mul(new_height) > SIXEL_PIXEL_LIMIT {
+            return Err(SixelError::InvalidDimensions {
+                width: new_width,
+                height: new_height,
+            });
         }
-        rgb_pixels.push(Srgb::new(c[0], c[1], c[2]));
     }
 
-    // Set up quantette pipeline
-    let max_colors = opts.max_colors.clamp(2, 256) as u8;
-    let palette_size = PaletteSize::try_from(max_colors).unwrap_or(PaletteSize::MAX);
+    if let Some(weights) = &opts.weight_map {
+        if weights.len() != width * height {
+            return Err(SixelError::BufferSizeMismatch {
+                expected: width * height,
+                actual: weights.len(),
+            });
+        }
+    }
+    let source_size = (width, height);
+
+    let resized;
+    let (view, width, height) = match target_size {
+        Some((new_width, new_height)) if (new_width, new_height) != (width, height) => {
+            opts.progress.report(EncodeStage::Resize, 0, 1)?;
+            let keyed;
+            let view = match opts.transparent_color {
+                Some(key) => {
+                    keyed = key_out(view, key);
+                    RgbaView::whole(&keyed, width, height)?
+                }
+                None => view,
+            };
+            resized = resize_rgba(view, new_width, new_height, opts.resize_filter);
+            (
+                RgbaView::region(&resized, new_width, new_height, 0, 0, new_width, new_height)?,
+                new_width,
+                new_height,
+            )
+        }
+        _ => (view, width, height),
+    };
+    let weights = opts.weight_map.as_deref().map(|weights| resize_weights(weights, source_size, (width, height)));
+    let weights = weights.as_deref();
+
+    let EncodeBuffers {
+        opacity_mask,
+        rgb_pixels,
+        band: band_buffers,
+    } = buffers;
+    prepare_pixels(view, opts.alpha, opacity_mask, rgb_pixels);
+    if let Some(key) = opts.transparent_color {
+        for (i, &c) in rgb_pixels.iter().enumerate() {
+            if opacity_mask.get(i) && is_key_color(c, key) {
+                opacity_mask.clear(i);
+            }
+        }
+    }
+    let background = elided_background(opts);
+    let background_mode = match background {
+        Some(background) => {
+            elide_background(rgb_pixels, opacity_mask, sixel_color(background, opts.palette_format), opts.palette_format);
+            BackgroundMode::Opaque
+        }
+        None => background_mode,
+    };
+    let (opacity_mask, rgb_pixels) = (&*opacity_mask, rgb_pixels.as_slice());
+
+    if let ColorMode::Monochrome { invert } = opts.color_mode {
+        opts.progress.report(EncodeStage::Dither, 0, 1)?;
+        let opacity_mask = monochrome_mask(rgb_pixels, opacity_mask, width, opts, invert);
+        let indices = vec![0u8; width * height];
+        return encode_indexed_to_sixel(
+            &[Rgb::default()],
+            &indices,
+            &[],
+            &opacity_mask,
+            width,
+            height,
+            pixel_aspect_ratio,
+            background_mode,
+            opts,
+            band_buffers,
+        );
+    }
+
+    if let ColorMode::Grayscale(levels) = opts.color_mode {
+        opts.progress.report(EncodeStage::Dither, 0, 1)?;
+        let diffusion = opts.diffusion.clamp(0.0, 1.0);
+        let dither = if diffusion <= 0.0 { Dither::None } else { opts.dither };
+        let local_strength = adaptive_dither_strength(rgb_pixels, width, opts);
+        let (grays, indices) = quantize_gray(rgb_pixels, width, levels, dither, diffusion, local_strength.as_deref(), opts.serpentine, |i| {
+            opacity_mask.get(i)
+        });
+        let palette: Vec<Rgb> = grays.into_iter().map(|g| Rgb::new(g, g, g)).collect();
+        return encode_indexed_to_sixel(
+            &palette,
+            &indices,
+            &[],
+            opacity_mask,
+            width,
+            height,
+            pixel_aspect_ratio,
+            background_mode,
+            opts,
+            band_buffers,
+        );
+    }
+
+    // The background takes register 0, the image colors follow.
+    let max_colors = opts.max_colors.clamp(2, 256) - u16::from(background.is_some());
+    let background_palette: Vec<Rgb> = background.into_iter().collect();
+
+    if let ColorMode::HighColor { bands_per_palette } = opts.color_mode {
+        let (mut indices, mut redefinitions) = band_palettes(rgb_pixels, opacity_mask, weights, width, height, opts, bands_per_palette, max_colors)?;
+        if background.is_some() {
+            indices.iter_mut().for_each(|index| *index += 1);
+            redefinitions.iter_mut().for_each(|redefinition| redefinition.register += 1);
+        }
+        return encode_indexed_to_sixel(
+            &background_palette,
+            &indices,
+            &redefinitions,
+            opacity_mask,
+            width,
+            height,
+            pixel_aspect_ratio,
+            background_mode,
+            opts,
+            band_buffers,
+        );
+    }
+
+    let (mut palette, mut indices) = color_palette(rgb_pixels, opacity_mask, weights, width, height, opts, max_colors)?;
+    if !background_palette.is_empty() {
+        palette.splice(0..0, background_palette);
+        indices.iter_mut().for_each(|index| *index += 1);
+    }
+
+    // Encode to SIXEL with transparency support
+    encode_indexed_to_sixel(
+        &palette,
+        &indices,
+        &[],
+        opacity_mask,
+        width,
+        height,
+        pixel_aspect_ratio,
+        background_mode,
+        opts,
+        band_buffers,
+    )
+}
+
+/// Whether `c` matches the color key of [`EncodeOptions::transparent_color`].
+#[inline]
+fn is_key_color(c: Srgb<u8>, (key, tolerance): (Rgb, u8)) -> bool {
+    c.red.abs_diff(key.r) <= tolerance && c.green.abs_diff(key.g) <= tolerance && c.blue.abs_diff(key.b) <= tolerance
+}
+
+/// Copy of `view` with the pixels matching `key` made fully transparent.
+fn key_out(view: RgbaView<'_>, key: (Rgb, u8)) -> Vec<u8> {
+    view.pixels()
+        .flat_map(|c| {
+            let alpha = if is_key_color(Srgb::new(c[0], c[1], c[2]), key) { 0 } else { c[3] };
+            [c[0], c[1], c[2], alpha]
+        })
+        .collect()
+}
+
+/// Leave the drawn pixels that are sent as `background` undrawn
+/// ([`EncodeOptions::background_color`]).
+fn elide_background(rgb_pixels: &[Srgb<u8>], opacity_mask: &mut BitMask, background: SixelColor, format: PaletteFormat) {
+    for (i, &c) in rgb_pixels.iter().enumerate() {
+        if opacity_mask.get(i) && sixel_color(srgb_to_rgb(c), format) == background {
+            opacity_mask.clear(i);
+        }
+    }
+}
+
+/// Compute a palette of at most `max_colors` colors for the drawn pixels and
+/// map the pixels onto it.
+pub(crate) fn color_palette(
+    rgb_pixels: &[Srgb<u8>],
+    opacity_mask: &BitMask,
+    weights: Option<&[u8]>,
+    width: usize,
+    height: usize,
+    opts: &EncodeOptions,
+    max_colors: u16,
+) -> Result<(Vec<Rgb>, Vec<u8>)> {
+    let fixed = fixed_palette(&opts.fixed_colors, max_colors as usize, opts.palette_format);
+
+    // Few unique colors (screenshots, diagrams, pixel art): emit them exactly,
+    // skipping quantization and dithering altogether. A custom palette is
+    // used as given, however few colors the image has.
+    let custom = matches!(opts.quantize_method, QuantizeMethod::CustomPalette(_));
+    if !custom {
+        if let Some(exact) = exact_palette(rgb_pixels, opacity_mask, &fixed, max_colors as usize, opts.palette_format) {
+            return Ok(exact);
+        }
+    }
+
+    // Palette colors closer together than the SIXEL color precision end
+    // up in the same register. With Wu, which is cheap, requantize once with
+    // a larger palette so the slots freed by merging hold distinct colors
+    // instead. The second pass reports no progress of its own.
+    opts.progress.report(EncodeStage::Quantize, 0, 1)?;
+    let mut best = quantize(rgb_pixels, opacity_mask, weights, width, height, opts, &fixed, max_colors)?;
+    let freed = merge_colliding_colors(&mut best.0, &mut best.1, opts.palette_format);
+    if freed > 0 && max_colors < PaletteSize::MAX.as_u16() && opts.quantize_method == QuantizeMethod::Wu {
+        let palette_size = (max_colors + freed as u16).min(PaletteSize::MAX.as_u16());
+        let silent = EncodeOptions {
+            progress: opts.progress.silent(),
+            ..opts.clone()
+        };
+        let mut candidate = quantize(rgb_pixels, opacity_mask, weights, width, height, &silent, &fixed, palette_size)?;
+        merge_colliding_colors(&mut candidate.0, &mut candidate.1, opts.palette_format);
+        if candidate.0.len() <= max_colors as usize {
+            best = candidate;
+        }
+    }
+    Ok(best)
+}
+
+/// Encode an already paletted image: pixels with `transparent_index` are
+/// left undrawn, everything else is sent with its palette entry as is.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn sixel_encode_indexed(
+    palette: &[Rgb],
+    indices: &[u8],
+    transparent_index: Option<u8>,
+    width: usize,
+    height: usize,
+    opts: &EncodeOptions,
+    pixel_aspect_ratio: PixelAspectRatio,
+    background_mode: BackgroundMode,
+) -> Result<String> {
+    let mut opacity_mask = BitMask::zeros(indices.len());
+    for (i, &index) in indices.iter().enumerate() {
+        if Some(index) != transparent_index {
+            opacity_mask.set(i);
+        }
+    }
 
-    // Create image reference for quantette
-    let image = ImageRef::new(width as u32, height as u32, &rgb_pixels).map_err(|e| SixelError::Quantization(e.to_string()))?;
+    let opts = EncodeOptions {
+        color_mode: ColorMode::Color,
+        background_color: None,
+        ..opts.clone()
+    };
+    encode_indexed_to_sixel(
+        palette,
+        indices,
+        &[],
+        &opacity_mask,
+        width,
+        height,
+        pixel_aspect_ratio,
+        background_mode,
+        &opts,
+        &mut BandBuffers::default(),
+    )
+}
 
-    // Use configured quantization method with diffusion-based dithering
+/// Quantize the drawn pixels with quantette and map every pixel onto the
+/// resulting palette using the configured dithering.
+///
+/// The palette starts with `fixed`; only the other `palette_size -
+/// fixed.len()` colors are picked from the pixels not sent as one of them.
+/// With `weights` the pixels count in proportion to their weight.
+#[allow(clippy::too_many_arguments)]
+fn quantize(
+    rgb_pixels: &[Srgb<u8>],
+    opacity_mask: &BitMask,
+    weights: Option<&[u8]>,
+    width: usize,
+    height: usize,
+    opts: &EncodeOptions,
+    fixed: &[Rgb],
+    palette_size: u16,
+) -> Result<(Vec<Rgb>, Vec<u8>)> {
+    let adaptive_size = PaletteSize::try_from_u16(palette_size.saturating_sub(fixed.len() as u16)).unwrap_or(PaletteSize::MAX);
+    let pipeline = Pipeline::new().palette_size(adaptive_size).quantize_method(opts.quantize_method.clone());
     let diffusion = opts.diffusion.clamp(0.0, 1.0);
-    let pipeline = Pipeline::new().palette_size(palette_size).quantize_method(opts.quantize_method.clone());
+    let fully_opaque = opacity_mask.count_ones() == width * height;
+    let quantette_dithers =
+        diffusion <= 0.0 || opts.dither == Dither::None || (opts.dither == Dither::FloydSteinberg && opts.serpentine && !opts.adaptive_diffusion);
+
+    if fixed.is_empty() && weights.is_none() && fully_opaque && quantette_dithers && opts.color_space == ColorSpace::Oklab {
+        // quantette handles plain mapping and (serpentine) Floyd-Steinberg itself
+        let image = ImageRef::new(width as u32, height as u32, rgb_pixels).map_err(|e| SixelError::Quantization(e.to_string()))?;
+        let indexed_image = if diffusion <= 0.0 || opts.dither == Dither::None {
+            // No dithering - sharp edges, may show banding
+            pipeline.ditherer(None).input_image(image).output_srgb8_indexed_image()
+        } else {
+            let ditherer = FloydSteinberg::with_error_diffusion(diffusion).unwrap_or_default();
+            pipeline.ditherer(ditherer).input_image(image).output_srgb8_indexed_image()
+        };
 
-    // Apply dithering based on diffusion setting
-    let indexed_image = if diffusion <= 0.0 {
-        // No dithering - sharp edges, may show banding
-        pipeline.ditherer(None).input_image(image).output_srgb8_indexed_image()
+        let palette: Vec<Rgb> = indexed_image.palette().iter().map(|&c| srgb_to_rgb(c)).collect();
+        Ok((palette, indexed_image.indices().to_vec()))
     } else {
-        // Use Floyd-Steinberg dithering with specified diffusion strength
-        let ditherer = FloydSteinberg::with_error_diffusion(diffusion).unwrap_or_default();
-        pipeline.ditherer(ditherer).input_image(image).output_srgb8_indexed_image()
-    };
+        // Pick the palette from the drawn pixels only, then dither onto it
+        // ourselves. Transparent pixels often carry garbage RGB; keeping them
+        // out of both steps leaves the visible result unaffected by them.
+        // Pixels already sent as a fixed color don't need adaptive entries.
+        let space = opts.color_space;
+        let working_pixels = space.convert_srgb8(rgb_pixels);
+        let fixed_registers: HashSet<SixelColor> = fixed.iter().map(|&c| sixel_color(c, opts.palette_format)).collect();
+        let adaptive = |i: usize| {
+            opacity_mask.get(i) && (fixed_registers.is_empty() || !fixed_registers.contains(&sixel_color(srgb_to_rgb(rgb_pixels[i]), opts.palette_format)))
+        };
+        let mut srgb_palette: Vec<Srgb<u8>> = fixed.iter().map(|c| Srgb::new(c.r, c.g, c.b)).collect();
+        let mut working_palette = space.convert_srgb8(&srgb_palette);
+        if (fixed.len() as u16) < palette_size {
+            let mut drawn: Vec<usize> = (0..rgb_pixels.len()).filter(|&i| adaptive(i)).collect();
+            if let Some(weights) = weights {
+                drawn = weighted_sample(&drawn, weights);
+            }
+            if space == ColorSpace::Oklab {
+                let drawn_pixels: Vec<Srgb<u8>> = drawn.iter().map(|&i| rgb_pixels[i]).collect();
+                if !drawn_pixels.is_empty() {
+                    let palette = pipeline.input_slice(&drawn_pixels).map_err(|e| SixelError::Quantization(e.to_string()))?;
+                    let palette = palette.output_srgb8_palette();
+                    working_palette.extend(space.convert_srgb8(palette.as_slice()));
+                    srgb_palette.extend_from_slice(palette.as_slice());
+                }
+            } else {
+                let drawn_pixels: Vec<[f32; 3]> = drawn.iter().map(|&i| working_pixels[i]).collect();
+                if !drawn_pixels.is_empty() {
+                    let palette = space.palette(&drawn_pixels, &opts.quantize_method, adaptive_size)?;
+                    srgb_palette.extend(space.to_srgb8(&palette));
+                    working_palette.extend(palette);
+                }
+            }
+        }
+        opts.progress.report(EncodeStage::Dither, 0, 1)?;
+        let dither = if diffusion <= 0.0 { Dither::None } else { opts.dither };
+        let local_strength = adaptive_dither_strength(rgb_pixels, width, opts);
+        let indices = dither_to_indices(
+            &working_pixels,
+            width,
+            &working_palette,
+            dither,
+            diffusion,
+            local_strength.as_deref(),
+            opts.serpentine,
+            |i| opacity_mask.get(i),
+        );
 
-    // Extract palette and indices
-    let palette: Vec<Rgb> = indexed_image
-        .palette()
+        let palette: Vec<Rgb> = srgb_palette.iter().map(|&c| srgb_to_rgb(c)).collect();
+        Ok((palette, indices))
+    }
+}
+
+/// Resample the `drawn` pixel indices so every pixel appears in proportion to
+/// its weight, keeping their number. Systematic resampling: the pixels under
+/// evenly spaced points of the cumulative weight. Uniform weights return
+/// `drawn` unchanged, all zero weights too.
+fn weighted_sample(drawn: &[usize], weights: &[u8]) -> Vec<usize> {
+    let total: u64 = drawn.iter().map(|&i| u64::from(weights[i])).sum();
+    if total == 0 {
+        return drawn.to_vec();
+    }
+    let n = drawn.len() as u64;
+    let mut samples = Vec::with_capacity(drawn.len());
+    let mut cumulative = 0u64;
+    for &i in drawn {
+        cumulative += u64::from(weights[i]);
+        // The k-th point lies at (k + 1/2) * total / n.
+        while (2 * samples.len() as u64 + 1) * total < 2 * cumulative * n {
+            samples.push(i);
+        }
+    }
+    samples
+}
+
+/// Nearest neighbor resample of [`EncodeOptions::weight_map`] to the
+/// resized image.
+fn resize_weights(weights: &[u8], (width, height): (usize, usize), (new_width, new_height): (usize, usize)) -> Cow<'_, [u8]> {
+    if (new_width, new_height) == (width, height) {
+        return Cow::Borrowed(weights);
+    }
+    (0..new_height)
+        .flat_map(|y| {
+            let row = (y * height / new_height) * width;
+            (0..new_width).map(move |x| weights[row + x * width / new_width])
+        })
+        .collect()
+}
+
+/// Per-pixel dithering strength if [`EncodeOptions::adaptive_diffusion`] is
+/// set and there is anything to dither.
+fn adaptive_dither_strength(rgb_pixels: &[Srgb<u8>], width: usize, opts: &EncodeOptions) -> Option<Vec<f32>> {
+    let dithers = opts.diffusion > 0.0 && opts.dither != Dither::None;
+    (opts.adaptive_diffusion && dithers).then(|| adaptive_strength(rgb_pixels, width))
+}
+
+/// Threshold or dither the luminance of the drawn pixels to 1 bit. Returns
+/// the mask of pixels whose sixel bit is set.
+fn monochrome_mask(rgb_pixels: &[Srgb<u8>], opacity_mask: &BitMask, width: usize, opts: &EncodeOptions, invert: bool) -> BitMask {
+    let diffusion = opts.diffusion.clamp(0.0, 1.0);
+    let dither = if diffusion <= 0.0 { Dither::None } else { opts.dither };
+    let luminance: Vec<[f32; 3]> = rgb_pixels.iter().map(|&c| [luminance(c); 3]).collect();
+    let local_strength = adaptive_dither_strength(rgb_pixels, width, opts);
+    let indices = dither_to_indices(
+        &luminance,
+        width,
+        &[[0.0; 3], [1.0; 3]],
+        dither,
+        diffusion,
+        local_strength.as_deref(),
+        opts.serpentine,
+        |i| opacity_mask.get(i),
+    );
+
+    let set_index = if invert { 0 } else { 1 };
+    let mut mask = BitMask::zeros(rgb_pixels.len());
+    for (i, &index) in indices.iter().enumerate() {
+        if index == set_index && opacity_mask.get(i) {
+            mask.set(i);
+        }
+    }
+    mask
+}
+
+/// Returns the exact palette and indices if the drawn pixels use at most
+/// `max_colors` distinct colors together with `fixed`, counting colors that
+/// are sent identically in `format` as one.
+fn exact_palette(rgb_pixels: &[Srgb<u8>], opacity_mask: &BitMask, fixed: &[Rgb], max_colors: usize, format: PaletteFormat) -> Option<(Vec<Rgb>, Vec<u8>)> {
+    let mut lookup: HashMap<u32, u8> = HashMap::new();
+    let mut registers: HashMap<SixelColor, u8> = fixed.iter().enumerate().map(|(index, &c)| (sixel_color(c, format), index as u8)).collect();
+    let mut palette: Vec<Rgb> = fixed.to_vec();
+    let mut indices = vec![0u8; rgb_pixels.len()];
+
+    for (i, &c) in rgb_pixels.iter().enumerate() {
+        if !opacity_mask.get(i) {
+            continue;
+        }
+        let key = (c.red as u32) << 16 | (c.green as u32) << 8 | c.blue as u32;
+        indices[i] = match lookup.get(&key) {
+            Some(&index) => index,
+            None => {
+                let color = srgb_to_rgb(c);
+                let index = match registers.get(&sixel_color(color, format)) {
+                    Some(&index) => index,
+                    None => {
+                        if palette.len() == max_colors {
+                            return None;
+                        }
+                        let index = palette.len() as u8;
+                        palette.push(color);
+                        registers.insert(sixel_color(color, format), index);
+                        index
+                    }
+                };
+                lookup.insert(key, index);
+                index
+            }
+        };
+    }
+
+    if palette.is_empty() {
+        // Nothing visible: a single placeholder register, no sixel data.
+        palette.push(Rgb::default());
+    }
+    Some((palette, indices))
+}
+
+/// The [`EncodeOptions::fixed_colors`] that fit into `max_colors`, each sent
+/// differently in `format`.
+fn fixed_palette(fixed_colors: &[Rgb], max_colors: usize, format: PaletteFormat) -> Vec<Rgb> {
+    let mut registers = HashSet::new();
+    let mut palette: Vec<Rgb> = fixed_colors.iter().copied().filter(|&c| registers.insert(sixel_color(c, format))).collect();
+    palette.truncate(max_colors);
+    palette
+}
+
+/// Merge palette entries that are sent identically in `format` and remap
+/// `indices` accordingly. Returns the number of freed palette slots.
+fn merge_colliding_colors(palette: &mut Vec<Rgb>, indices: &mut [u8], format: PaletteFormat) -> usize {
+    let mut registers: HashMap<SixelColor, u8> = HashMap::new();
+    let mut merged: Vec<Rgb> = Vec::with_capacity(palette.len());
+    let remap: Vec<u8> = palette
         .iter()
-        .map(|c| Rgb {
-            r: c.red,
-            g: c.green,
-            b: c.blue,
+        .map(|&color| {
+            *registers.entry(sixel_color(color, format)).or_insert_with(|| {
+                merged.push(color);
+                (merged.len() - 1) as u8
+            })
         })
         .collect();
 
-    let indices: Vec<u8> = indexed_image.indices().to_vec();
+    let freed = palette.len() - merged.len();
+    if freed > 0 {
+        for index in indices.iter_mut() {
+            *index = remap[*index as usize];
+        }
+        *palette = merged;
+    }
+    freed
+}
 
-    // Encode to SIXEL with transparency support
-    encode_indexed_to_sixel(&palette, &indices, &opacity_mask, width, height, pixel_aspect_ratio, background_mode)
+/// Color introducer parameters: the color coordinate system (1 = HLS,
+/// 2 = RGB) followed by its three components.
+pub(crate) type SixelColor = (u8, [u16; 3]);
+
+/// The color introducer parameters that define `color` in `format`.
+pub(crate) fn sixel_color(color: Rgb, format: PaletteFormat) -> SixelColor {
+    match format {
+        // Round to the nearest percent; the decoder maps back with rounding too.
+        PaletteFormat::Rgb => (2, [color.r, color.g, color.b].map(|c| ((c as u32 * 100 + 127) / 255) as u16)),
+        PaletteFormat::Hls => (1, rgb_to_hls(color)),
+    }
+}
+
+/// Convert `color` to SIXEL HLS (hue 0-360 with blue at 0 degrees,
+/// lightness and saturation 0-100).
+///
+/// Rounding each component separately isn't always the closest match, so
+/// the neighbors of the rounded value are tried as well.
+fn rgb_to_hls(color: Rgb) -> [u16; 3] {
+    let [r, g, b] = [color.r, color.g, color.b].map(|c| c as f32 / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.0;
+    let delta = max - min;
+    if delta == 0.0 {
+        return [0, (lightness * 100.0).round() as u16, 0];
+    }
+
+    let saturation = if lightness > 0.5 { delta / (2.0 - max - min) } else { delta / (max + min) };
+    let hue = if max == r {
+        (g - b) / delta + if g < b { 6.0 } else { 0.0 }
+    } else if max == g {
+        (b - r) / delta + 2.0
+    } else {
+        (r - g) / delta + 4.0
+    } * 60.0;
+
+    // SIXEL hue puts blue at 0 degrees, red at 120 and green at 240.
+    let h = (hue.round() as i32 + 120).rem_euclid(360);
+    let l = (lightness * 100.0).round() as i32;
+    let s = (saturation * 100.0).round() as i32;
+
+    let mut best = [h, l, s];
+    let mut best_error = u32::MAX;
+    for dh in -1..=1 {
+        for dl in -1..=1 {
+            for ds in -1..=1 {
+                let candidate = [(h + dh).rem_euclid(360), (l + dl).clamp(0, 100), (s + ds).clamp(0, 100)];
+                let decoded = crate::decoder::hls_to_rgb(candidate[0], candidate[1], candidate[2]);
+                let error = [color.r, color.g, color.b]
+                    .iter()
+                    .zip(decoded)
+                    .map(|(&a, b)| (a as i32 - b as i32).pow(2) as u32)
+                    .sum();
+                if error < best_error {
+                    best = candidate;
+                    best_error = error;
+                }
+            }
+        }
+    }
+    best.map(|c| c as u16)
+}
+
+/// Build the transparency mask (set bit = drawn) and the `Srgb<u8>` pixels
+/// used for quantization (quantette uses palette crate types), overwriting
+/// the previous contents of both.
+fn prepare_pixels(view: RgbaView<'_>, alpha: AlphaMode, opacity_mask: &mut BitMask, rgb_pixels: &mut Vec<Srgb<u8>>) {
+    let width = view.width;
+    let pixel_count = width * view.height;
+    opacity_mask.reset(pixel_count);
+    rgb_pixels.clear();
+    rgb_pixels.reserve(pixel_count);
+
+    if alpha == AlphaMode::DiffusionDither {
+        // Floyd-Steinberg on the alpha channel, two rows of error.
+        let mut this_err = vec![0.0f32; width + 2];
+        let mut next_err = vec![0.0f32; width + 2];
+        for (y, row) in view.rows().enumerate() {
+            for (x, c) in row.chunks_exact(4).enumerate() {
+                let value = c[3] as f32 / 255.0 + this_err[x + 1];
+                let drawn = value >= 0.5;
+                if drawn {
+                    opacity_mask.set(y * width + x);
+                }
+                let err = value - if drawn { 1.0 } else { 0.0 };
+                this_err[x + 2] += err * 7.0 / 16.0;
+                next_err[x] += err * 3.0 / 16.0;
+                next_err[x + 1] += err * 5.0 / 16.0;
+                next_err[x + 2] += err / 16.0;
+                rgb_pixels.push(Srgb::new(c[0], c[1], c[2]));
+            }
+            core::mem::swap(&mut this_err, &mut next_err);
+            next_err.fill(0.0);
+        }
+        return;
+    }
+
+    #[cfg(feature = "rayon")]
+    if use_threads(pixel_count) {
+        use rayon::prelude::*;
+
+        rgb_pixels.resize(pixel_count, Srgb::new(0, 0, 0));
+        rgb_pixels.par_chunks_mut(width).enumerate().for_each(|(y, row)| {
+            for (pixel, c) in row.iter_mut().zip(view.row(y).chunks_exact(4)) {
+                *pixel = pixel_color(alpha, c);
+            }
+        });
+        opacity_mask.words.par_iter_mut().enumerate().for_each(|(word_index, word)| {
+            for i in word_index * 64..usize::min(word_index * 64 + 64, pixel_count) {
+                if is_drawn(alpha, width, i, view.pixel(i)) {
+                    *word |= 1u64 << (i & 63);
+                }
+            }
+        });
+        return;
+    }
+
+    // Single pass building both the mask and the pixels.
+    for (i, c) in view.pixels().enumerate() {
+        if is_drawn(alpha, width, i, c) {
+            opacity_mask.set(i);
+        }
+        rgb_pixels.push(pixel_color(alpha, c));
+    }
+}
+
+/// Whether pixel `i` with RGBA value `c` is drawn, for the alpha modes that
+/// decide every pixel on its own (all but [`AlphaMode::DiffusionDither`]).
+#[inline]
+fn is_drawn(alpha: AlphaMode, width: usize, i: usize, c: &[u8]) -> bool {
+    match alpha {
+        AlphaMode::Threshold(threshold) => c[3] >= threshold,
+        AlphaMode::OrderedDither => c[3] as f32 / 255.0 > ordered_threshold(Dither::Bayer8x8, i % width, i / width).unwrap_or(0.0) + 0.5,
+        AlphaMode::Composite(_) => c[3] > 0,
+        AlphaMode::DiffusionDither => unreachable!("alpha diffusion depends on the neighbors"),
+    }
+}
+
+/// The color quantization sees for the RGBA value `c`.
+#[inline]
+fn pixel_color(alpha: AlphaMode, c: &[u8]) -> Srgb<u8> {
+    match alpha {
+        AlphaMode::Composite(background) => {
+            let blend = |c: u8, bg: u8, a: u8| ((c as u32 * a as u32 + bg as u32 * (255 - a as u32) + 127) / 255) as u8;
+            Srgb::new(
+                blend(c[0], background.r, c[3]),
+                blend(c[1], background.g, c[3]),
+                blend(c[2], background.b, c[3]),
+            )
+        }
+        _ => Srgb::new(c[0], c[1], c[2]),
+    }
+}
+
+#[inline]
+fn srgb_to_rgb(c: Srgb<u8>) -> Rgb {
+    Rgb {
+        r: c.red,
+        g: c.green,
+        b: c.blue,
+    }
 }
 
 /// Encode RGBA with default options.
@@ -187,56 +1303,160 @@ pub fn sixel_encode_default(rgba: &[u8], width: usize, height: usize) -> Result<
     sixel_encode(rgba, width, height, &EncodeOptions::default())
 }
 
+/// The background color left undrawn, if elision applies to the color mode.
+fn elided_background(opts: &EncodeOptions) -> Option<Rgb> {
+    opts.background_color
+        .filter(|_| matches!(opts.color_mode, ColorMode::Color | ColorMode::HighColor { .. }))
+}
+
+#[allow(clippy::too_many_arguments)]
 fn encode_indexed_to_sixel(
     palette: &[Rgb],
     indices: &[u8],
+    redefinitions: &[Redefinition],
     opacity_mask: &BitMask,
     width: usize,
     height: usize,
     aspect_ratio: PixelAspectRatio,
     background_mode: BackgroundMode,
+    opts: &EncodeOptions,
+    buffers: &mut BandBuffers,
 ) -> Result<String> {
-    let mut out = String::new();
+    let mut out = std::mem::take(&mut buffers.out);
+    out.clear();
 
     // DCS introducer for SIXEL: ESC P p1 ; p2 ; p3 q
     // p1=aspect ratio, p2=background mode, p3=0 (grid size default)
-    out.push_str("\x1bP");
+    out.push_str(opts.framing.dcs());
     write_number(&mut out, aspect_ratio.to_p1_value() as usize);
     out.push(';');
     write_number(&mut out, background_mode.to_p2_value() as usize);
     out.push_str(";0q");
+    let header_len = out.len();
 
-    // Define palette in RGB percent (0-100)
-    for (i, c) in palette.iter().enumerate() {
-        let r = (c.r as u32 * 100) / 255;
-        let g = (c.g as u32 * 100) / 255;
-        let b = (c.b as u32 * 100) / 255;
-        out.push('#');
-        write_number(&mut out, i);
-        out.push(';');
-        out.push('2');
+    // Monochrome output draws set bits in the foreground color and has no
+    // color definitions or introducers at all.
+    let monochrome = matches!(opts.color_mode, ColorMode::Monochrome { .. });
+
+    // Define palette in RGB percent (0-100) or HLS
+    for (i, &c) in palette.iter().enumerate().filter(|_| !monochrome) {
+        write_color_definition(&mut out, i, sixel_color(c, opts.palette_format));
+    }
+
+    // With an elided background or trimmed rows the image declares its full
+    // size, as decoders otherwise size it by what was drawn: undrawn margins
+    // at the right and bottom are kept and, in opaque mode, filled with the
+    // background. This follows the palette: decoders fill the raster area
+    // with register 0 as defined at this point.
+    if opts.optimize_size || elided_background(opts).is_some() {
+        out.push('"');
+        write_number(&mut out, aspect_ratio.pan() as usize);
         out.push(';');
-        write_number(&mut out, r as usize);
+        write_number(&mut out, aspect_ratio.pad() as usize);
         out.push(';');
-        write_number(&mut out, g as usize);
+        write_number(&mut out, width);
         out.push(';');
-        write_number(&mut out, b as usize);
+        write_number(&mut out, height);
+    }
+
+    let palette_len = redefinitions.iter().map(|r| r.register + 1).fold(palette.len(), usize::max);
+    let source = BandSource {
+        indices,
+        opacity_mask,
+        width,
+        height,
+        bands: height.div_ceil(6),
+        palette_len,
+        monochrome,
+        optimize_size: opts.optimize_size,
+    };
+    #[cfg(feature = "rayon")]
+    if use_threads(width * height) {
+        write_bands_parallel(&mut out, &source, redefinitions, &opts.progress)?;
+    } else {
+        write_bands(&mut out, &source, redefinitions, &opts.progress, buffers)?;
     }
+    #[cfg(not(feature = "rayon"))]
+    write_bands(&mut out, &source, redefinitions, &opts.progress, buffers)?;
+    let bands = source.bands;
 
-    let bands = height.div_ceil(6);
-    let palette_len = palette.len();
+    opts.progress.report(EncodeStage::Bands, bands, bands)?;
 
-    // Scratch buffer holding the 6-bit sixel value for every (color, column)
-    // pair in the current band. Reused across bands; only the rows of colors
-    // actually used in a band are cleared, so this stays cheap.
-    let mut sixels = vec![0u8; palette_len * width];
-    let mut colors_used = vec![false; palette_len];
+    // String terminator: ESC \
+    out.push_str(opts.framing.st());
 
-    for band in 0..bands {
-        let y0 = band * 6;
-        let y_max = usize::min(y0 + 6, height);
+    if opts.printer.is_some_and(|printer| printer.form_feed) {
+        out.push('\x0c');
+    }
+
+    Ok(opts.framing.apply(out, header_len))
+}
+
+/// Write the color definition `#register;space;x;y;z`.
+fn write_color_definition(out: &mut String, register: usize, (space, [x, y, z]): SixelColor) {
+    out.push('#');
+    write_number(out, register);
+    out.push(';');
+    write_number(out, space as usize);
+    out.push(';');
+    write_number(out, x as usize);
+    out.push(';');
+    write_number(out, y as usize);
+    out.push(';');
+    write_number(out, z as usize);
+}
+
+/// Order the colors of a band so the stream needs fewer `#n` introducers.
+///
+/// The color that is still selected from the previous band goes first, and a
+/// color that also appears in the next band goes last, so both band
+/// boundaries can skip re-selecting the color register.
+fn order_band_colors(order: &mut [usize], current_color: Option<usize>, in_next_band: impl Fn(usize) -> bool) {
+    if let Some(position) = current_color.and_then(|current| order.iter().position(|&c| c == current)) {
+        order[..=position].rotate_right(1);
+    }
+    if order.len() < 2 {
+        return;
+    }
+    if let Some(position) = order[1..].iter().rposition(|&c| in_next_band(c)) {
+        order[position + 1..].rotate_left(1);
+    }
+}
 
-        // Reset only the rows touched by the previous band.
+/// A paletted image to be written as SIXEL bands.
+struct BandSource<'a> {
+    indices: &'a [u8],
+    opacity_mask: &'a BitMask,
+    width: usize,
+    height: usize,
+    bands: usize,
+    /// Number of color registers the indices refer to.
+    palette_len: usize,
+    monochrome: bool,
+    optimize_size: bool,
+}
+
+impl BandSource<'_> {
+    /// Call `mark` with the color of every drawn pixel of `band`.
+    fn for_each_color(&self, band: usize, mut mark: impl FnMut(usize)) {
+        for y in band * 6..usize::min(band * 6 + 6, self.height) {
+            let row = y * self.width;
+            for x in 0..self.width {
+                if self.opacity_mask.get(row + x) {
+                    mark(self.indices[row + x] as usize);
+                }
+            }
+        }
+    }
+
+    /// Scatter the drawn pixels of `band` into `sixels`, which holds the
+    /// 6-bit sixel value for every (color, column) pair, and flag their
+    /// colors in `colors_used`. `columns` is scratch space.
+    ///
+    /// Only the rows of the colors flagged by the previous band are cleared
+    /// first, so reusing the buffers for every band stays cheap.
+    fn scatter(&self, band: usize, sixels: &mut [u8], colors_used: &mut [bool], columns: &mut Vec<u8>) {
+        let width = self.width;
         for (color_index, used) in colors_used.iter_mut().enumerate() {
             if *used {
                 sixels[color_index * width..(color_index + 1) * width].fill(0);
@@ -244,47 +1464,80 @@ fn encode_indexed_to_sixel(
             }
         }
 
-        // Single pass over the band: scatter each opaque pixel's bit into the
-        // scratch buffer keyed by its color. This replaces the previous
-        // O(colors x pixels) re-scan with a single O(pixels) pass.
-        for y in y0..y_max {
-            let bit = 1u8 << (y - y0);
-            let row = y * width;
-            for x in 0..width {
-                let pixel_idx = row + x;
-                if opacity_mask.get(pixel_idx) {
-                    let color_index = indices[pixel_idx] as usize;
-                    sixels[color_index * width + x] |= bit;
-                    colors_used[color_index] = true;
+        // Sixel bits of the drawn pixels of every column, gathered 64 pixels
+        // at a time from the opacity mask.
+        let y0 = band * 6;
+        let band_rows = usize::min(6, self.height - y0);
+        let padded_width = width.next_multiple_of(64);
+        columns.clear();
+        columns.resize(padded_width + width, 0);
+        let (opaque, same) = columns.split_at_mut(padded_width);
+        for k in 0..band_rows {
+            let row = (y0 + k) * width;
+            for (chunk, opaque) in opaque.chunks_exact_mut(64).enumerate() {
+                let word = self.opacity_mask.word_at(row + chunk * 64);
+                if word != 0 {
+                    spread_bits(word, k, opaque.try_into().unwrap());
                 }
             }
         }
 
+        // Most columns have a single color; its bits are set with one store
+        // and only the remaining pixels are scattered one by one.
+        let rows: [&[u8]; 6] = std::array::from_fn(|k| &self.indices[(y0 + k.min(band_rows - 1)) * width..][..width]);
+        same_color_bits(&rows, opaque, same);
+        for x in 0..width {
+            let same_bits = same[x];
+            if same_bits != 0 {
+                let color_index = rows[0][x] as usize;
+                sixels[color_index * width + x] |= same_bits;
+                colors_used[color_index] = true;
+            }
+            let mut rest = opaque[x] & !same_bits;
+            while rest != 0 {
+                let k = rest.trailing_zeros() as usize;
+                let color_index = rows[k][x] as usize;
+                sixels[color_index * width + x] |= 1 << k;
+                colors_used[color_index] = true;
+                rest &= rest - 1;
+            }
+        }
+    }
+
+    /// Write the colors of `band` in `order` from the scattered `sixels`,
+    /// followed by the move to the next band.
+    ///
+    /// `current_color` is the selected color register; it stays selected
+    /// across `$` and `-` and is updated to the last color written.
+    fn write(&self, out: &mut String, band: usize, order: &[usize], current_color: &mut Option<usize>, sixels: &[u8]) {
+        let width = self.width;
+
         // Emit each used color, run-length encoding consecutive identical sixels.
-        for color_index in 0..palette_len {
-            if !colors_used[color_index] {
-                continue; // Skip colors not used in this band
+        for (position, &color_index) in order.iter().enumerate() {
+            if !self.monochrome && (!self.optimize_size || *current_color != Some(color_index)) {
+                out.push('#');
+                write_number(out, color_index);
+                *current_color = Some(color_index);
             }
 
-            // Select color map register
-            out.push('#');
-            write_number(&mut out, color_index);
+            let mut row = &sixels[color_index * width..(color_index + 1) * width];
+            if self.optimize_size {
+                // Undrawn sixels at the end of a row don't need to be sent
+                let used_len = row.iter().rposition(|&bits| bits != 0).map_or(0, |last| last + 1);
+                row = &row[..used_len];
+            }
 
-            let row = &sixels[color_index * width..(color_index + 1) * width];
             let mut x = 0;
-            while x < width {
+            while x < row.len() {
                 let bits = row[x];
 
                 // Run-length encode consecutive identical sixel values
-                let mut run_len = 1usize;
-                while x + run_len < width && row[x + run_len] == bits {
-                    run_len += 1;
-                }
+                let run_len = run_length(row, x);
 
-                // Write RLE or raw sixels
+                // Write RLE or raw sixels; `!<n><char>` is shorter from 4 on
                 if run_len > 3 {
                     out.push('!');
-                    write_number(&mut out, run_len);
+                    write_number(out, run_len);
                     out.push((63 + bits) as char);
                 } else {
                     let ch = (63 + bits) as char;
@@ -295,19 +1548,157 @@ fn encode_indexed_to_sixel(
                 x += run_len;
             }
 
-            // Carriage return to start of band for next color overlay
-            out.push('$');
+            // Carriage return to start of band for next color overlay; not
+            // needed after the last color since `-` returns to column 0 as well
+            if !self.optimize_size || position + 1 < order.len() {
+                out.push('$');
+            }
         }
 
         // Move to next band
-        out.push('-');
+        if !self.optimize_size || band + 1 < self.bands {
+            out.push('-');
+        }
     }
+}
 
-    // String terminator: ESC \
-    out.push('\x1b');
-    out.push('\\');
+/// Write the bands of `source` one after the other, reusing `buffers`.
+fn write_bands(out: &mut String, source: &BandSource<'_>, redefinitions: &[Redefinition], progress: &ProgressHook, buffers: &mut BandBuffers) -> Result<()> {
+    let BandBuffers {
+        sixels,
+        colors_used,
+        columns,
+        order,
+        next_band_stamp,
+        ..
+    } = buffers;
+    sixels.clear();
+    sixels.resize(source.palette_len * source.width, 0);
+    colors_used.clear();
+    colors_used.resize(source.palette_len, false);
+
+    // Emission order of the colors used in the current band, the currently
+    // selected color register and, for `optimize_size`, the index of the
+    // last band each color was seen in by the look-ahead.
+    order.clear();
+    let mut current_color: Option<usize> = None;
+    next_band_stamp.clear();
+    if source.optimize_size {
+        next_band_stamp.resize(source.palette_len, usize::MAX);
+    }
+
+    let mut redefinitions = redefinitions.iter().peekable();
+    for band in 0..source.bands {
+        progress.report(EncodeStage::Bands, band, source.bands)?;
+
+        // Registers redefined for this band. A definition also selects its
+        // register, so the selected color is no longer known.
+        while let Some(redefinition) = redefinitions.next_if(|r| r.band == band) {
+            write_color_definition(out, redefinition.register, redefinition.color);
+            current_color = None;
+        }
+
+        source.scatter(band, sixels, colors_used, columns);
+        order.clear();
+        order.extend((0..source.palette_len).filter(|&color_index| colors_used[color_index]));
+        if source.optimize_size {
+            let next_band = band + 1;
+            if next_band < source.bands && order.len() > 1 {
+                source.for_each_color(next_band, |color_index| next_band_stamp[color_index] = next_band);
+            }
+            order_band_colors(order, current_color, |color_index| next_band_stamp[color_index] == next_band);
+        }
+        source.write(out, band, order, &mut current_color, sixels);
+    }
+    Ok(())
+}
+
+/// Images with fewer pixels are written on the calling thread; splitting
+/// them up costs more than it saves.
+#[cfg(feature = "rayon")]
+const PARALLEL_MIN_PIXELS: usize = 1 << 16;
+
+/// Whether to spread the work for `pixel_count` pixels over the rayon pool.
+/// With a single thread the parallel paths only add overhead.
+#[cfg(feature = "rayon")]
+fn use_threads(pixel_count: usize) -> bool {
+    pixel_count >= PARALLEL_MIN_PIXELS && rayon::current_num_threads() > 1
+}
+
+/// Write the bands of `source` on the rayon thread pool. The output is the
+/// same as that of [`write_bands`].
+///
+/// Only the register selected at the start of each band carries over from
+/// the previous one. It follows from the colors of the bands alone, so those
+/// are collected first, the color order of every band is decided in a short
+/// serial pass, and then the bands are written in parallel.
+#[cfg(feature = "rayon")]
+fn write_bands_parallel(out: &mut String, source: &BandSource<'_>, redefinitions: &[Redefinition], progress: &ProgressHook) -> Result<()> {
+    use rayon::prelude::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let band_colors: Vec<Vec<usize>> = (0..source.bands)
+        .into_par_iter()
+        .map_init(
+            || vec![false; source.palette_len],
+            |used, band| {
+                source.for_each_color(band, |color_index| used[color_index] = true);
+                let colors = (0..source.palette_len).filter(|&color_index| used[color_index]).collect();
+                used.fill(false);
+                colors
+            },
+        )
+        .collect();
+
+    let band_redefinitions = |band: usize| {
+        let start = redefinitions.partition_point(|r| r.band < band);
+        let end = redefinitions.partition_point(|r| r.band <= band);
+        &redefinitions[start..end]
+    };
 
-    Ok(out)
+    // Color order and selected register at the start of every band.
+    let mut current_color: Option<usize> = None;
+    let mut plans: Vec<(Vec<usize>, Option<usize>)> = Vec::with_capacity(source.bands);
+    for (band, colors) in band_colors.iter().enumerate() {
+        if !band_redefinitions(band).is_empty() {
+            current_color = None;
+        }
+        let mut order = colors.clone();
+        if source.optimize_size {
+            let next_colors = band_colors.get(band + 1).map_or(&[][..], Vec::as_slice);
+            order_band_colors(&mut order, current_color, |color_index| next_colors.binary_search(&color_index).is_ok());
+        }
+        let start_color = current_color;
+        if let Some(&last) = order.last().filter(|_| !source.monochrome) {
+            current_color = Some(last);
+        }
+        plans.push((order, start_color));
+    }
+
+    let started = AtomicUsize::new(0);
+    let band_strings: Vec<String> = plans
+        .par_iter()
+        .enumerate()
+        .map_init(
+            || (vec![0u8; source.palette_len * source.width], vec![false; source.palette_len], Vec::new()),
+            |(sixels, colors_used, columns), (band, &(ref order, mut current_color))| {
+                progress.report(EncodeStage::Bands, started.fetch_add(1, Ordering::Relaxed), source.bands)?;
+                let mut band_out = String::new();
+                for redefinition in band_redefinitions(band) {
+                    write_color_definition(&mut band_out, redefinition.register, redefinition.color);
+                }
+                source.scatter(band, sixels, colors_used, columns);
+                source.write(&mut band_out, band, order, &mut current_color, sixels);
+                Ok(band_out)
+            },
+        )
+        .collect::<Result<_>>()?;
+
+    out.reserve(band_strings.iter().map(String::len).sum());
+    for band_out in &band_strings {
+        out.push_str(band_out);
+    }
+    Ok(())
 }
 
 /// Fast number to string without allocation
@@ -381,4 +1772,727 @@ mod tests {
         assert!(sixel_encode(&rgba, 4, 0, &EncodeOptions::default()).is_err());
         assert!(sixel_encode(&rgba, 10, 10, &EncodeOptions::default()).is_err());
     }
+
+    #[test]
+    fn test_encode_all_dithers_roundtrip() {
+        let (width, height) = (32, 12);
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| [(i % width * 8) as u8, (i / width * 20) as u8, 128, 255])
+            .collect();
+        for dither in [
+            Dither::None,
+            Dither::FloydSteinberg,
+            Dither::Atkinson,
+            Dither::JarvisJudiceNinke,
+            Dither::Stucki,
+            Dither::Sierra,
+            Dither::Bayer2x2,
+            Dither::Bayer4x4,
+            Dither::Bayer8x8,
+            Dither::BlueNoise,
+        ] {
+            for serpentine in [true, false] {
+                let opts = EncodeOptions {
+                    max_colors: 8,
+                    dither,
+                    serpentine,
+                    ..Default::default()
+                };
+                let sixel = sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap();
+                let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+                assert_eq!((decoded.width, decoded.height), (width, height), "{dither:?}");
+            }
+        }
+    }
+
+    fn drawn_pixels(sixel: &str) -> usize {
+        let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+        decoded.pixels.chunks_exact(4).filter(|c| c[3] != 0).count()
+    }
+
+    #[test]
+    fn test_alpha_modes() {
+        // 16x6 pixels of white at 25% alpha
+        let rgba: Vec<u8> = [255, 255, 255, 64].repeat(16 * 6);
+        let encode = |alpha| {
+            let opts = EncodeOptions { alpha, ..Default::default() };
+            sixel_encode_impl(&rgba, 16, 6, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap()
+        };
+
+        assert_eq!(drawn_pixels(&encode(AlphaMode::Threshold(128))), 0);
+        assert_eq!(drawn_pixels(&encode(AlphaMode::Threshold(64))), 96);
+        for mode in [AlphaMode::OrderedDither, AlphaMode::DiffusionDither] {
+            let drawn = drawn_pixels(&encode(mode));
+            assert!((16..=32).contains(&drawn), "{mode:?} drew {drawn} pixels");
+        }
+
+        // White at 25% over black composites to ~25% gray, all pixels drawn.
+        let sixel = encode(AlphaMode::Composite(Rgb::new(0, 0, 0)));
+        assert_eq!(drawn_pixels(&sixel), 96);
+        assert!(sixel.contains(";2;25;25;25"), "{sixel}");
+    }
+
+    #[test]
+    fn test_transparent_pixels_do_not_affect_palette() {
+        // Left half: fully transparent bright green garbage, right half: opaque grays.
+        let (width, height) = (16, 6);
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let x = i % width;
+                if x < 8 {
+                    [0, 255, 0, 0]
+                } else {
+                    let v = (x * 16) as u8;
+                    [v, v, v, 255]
+                }
+            })
+            .collect();
+        let opts = EncodeOptions {
+            max_colors: 4,
+            ..Default::default()
+        };
+        let sixel = sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap();
+        let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+        for c in decoded.pixels.chunks_exact(4).filter(|c| c[3] != 0) {
+            assert!(c[0] == c[1] && c[1] == c[2], "visible pixel {c:?} is not gray");
+        }
+
+        // Entirely transparent images still encode.
+        let rgba = [0u8, 255, 0, 0].repeat(width * height);
+        assert!(sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).is_ok());
+    }
+
+    #[test]
+    fn test_optimize_size_decodes_identically() {
+        let (width, height) = (40, 20);
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                match (x / 10 + y / 5) % 4 {
+                    0 => [0, 0, 0, 0],
+                    1 => [255, 0, 0, 255],
+                    2 => [0, (x * 6) as u8, 255, 255],
+                    _ => [(y * 12) as u8, 200, 40, 255],
+                }
+            })
+            .collect();
+
+        for max_colors in [4, 16, 256] {
+            let encode = |optimize_size| {
+                let opts = EncodeOptions {
+                    max_colors,
+                    optimize_size,
+                    ..Default::default()
+                };
+                sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap()
+            };
+            let (plain, optimized) = (encode(false), encode(true));
+            assert!(optimized.len() < plain.len(), "{} >= {}", optimized.len(), plain.len());
+            assert!(!optimized.contains("$-"));
+            assert!(!optimized.ends_with("-\x1b\\"));
+
+            let plain = crate::SixelImage::decode(plain.as_bytes()).unwrap();
+            let optimized = crate::SixelImage::decode(optimized.as_bytes()).unwrap();
+            assert_eq!((plain.width, plain.height), (optimized.width, optimized.height));
+            assert!(plain.pixels == optimized.pixels, "decoded pixels differ with {max_colors} colors");
+        }
+
+        // Undrawn right edge and bottom band: the trimmed output still
+        // declares the full size.
+        let (width, height) = (20, 12);
+        for transparent in [|x: usize, _: usize| x >= 10, |_: usize, y: usize| y >= 6] {
+            let rgba: Vec<u8> = (0..width * height)
+                .flat_map(|i| if transparent(i % width, i / width) { [0, 0, 0, 0] } else { [255, 0, 0, 255] })
+                .collect();
+            let encode = |optimize_size| {
+                let opts = EncodeOptions {
+                    optimize_size,
+                    ..Default::default()
+                };
+                sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap()
+            };
+            let plain = crate::SixelImage::decode(encode(false).as_bytes()).unwrap();
+            let optimized = crate::SixelImage::decode(encode(true).as_bytes()).unwrap();
+            assert_eq!((optimized.width, optimized.height), (width, height));
+            assert_eq!(optimized.pixels, rgba);
+            // Plain output has no raster attributes and drops an undrawn bottom band.
+            assert_eq!(plain.pixels, rgba[..plain.pixels.len()]);
+        }
+    }
+
+    #[test]
+    fn test_custom_palette_with_few_colors() {
+        // Three colors fit `max_colors`, but the custom palette still applies.
+        let rgba = [[255, 0, 0, 255], [0, 255, 0, 255], [0, 0, 255, 255]].concat();
+        let palette = quantette::PaletteBuf::new(vec![Srgb::new(0u8, 0, 0), Srgb::new(255, 255, 255)]).unwrap();
+        let opts = EncodeOptions {
+            quantize_method: QuantizeMethod::from(palette),
+            ..Default::default()
+        };
+        let sixel = sixel_encode_impl(&rgba, 3, 1, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap();
+        assert!(sixel.starts_with("\x1bP9;1;0q#0;2;0;0;0#1;2;100;100;100#"), "{sixel}");
+        assert!(!sixel.contains("#2;"), "{sixel}");
+    }
+
+    #[test]
+    fn test_palette_percent_rounding_and_merging() {
+        assert_eq!(sixel_color(Rgb::new(254, 128, 3), PaletteFormat::Rgb), (2, [100, 50, 1]));
+
+        // Black and (1, 1, 1) are both sent as 0% and share one register.
+        let mut palette = vec![Rgb::new(0, 0, 0), Rgb::new(255, 0, 0), Rgb::new(1, 1, 1)];
+        let mut indices = vec![0, 1, 2, 2];
+        assert_eq!(merge_colliding_colors(&mut palette, &mut indices, PaletteFormat::Rgb), 1);
+        assert_eq!(palette, [Rgb::new(0, 0, 0), Rgb::new(255, 0, 0)]);
+        assert_eq!(indices, [0, 1, 0, 0]);
+
+        let rgba = [[0, 0, 0, 255], [1, 1, 1, 255], [255, 255, 255, 255]].concat();
+        let sixel = sixel_encode_impl(&rgba, 3, 1, &EncodeOptions::default(), PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap();
+        assert_eq!(sixel.matches(";2;").count(), 2, "{sixel}");
+    }
+
+    #[test]
+    fn test_requantize_reports_once() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        // A dark color cube: 343 distinct percent values, but Wu's palette
+        // colors collide, so merging frees registers and Wu requantizes.
+        // Progress still reports a single Quantize step.
+        let rgba: Vec<u8> = (0..64 * 64).flat_map(|i| [(i % 16) as u8, (i / 16 % 16) as u8, (i / 256) as u8, 255]).collect();
+        let quantize_reports = Arc::new(AtomicUsize::new(0));
+        let counter = quantize_reports.clone();
+        let opts = EncodeOptions {
+            max_colors: 128,
+            progress: ProgressHook::new().with_callback(move |p| {
+                if p.stage == EncodeStage::Quantize {
+                    counter.fetch_add(1, Ordering::Relaxed);
+                }
+            }),
+            ..Default::default()
+        };
+        let sixel = sixel_encode_impl(&rgba, 64, 64, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap();
+        assert_eq!(quantize_reports.load(Ordering::Relaxed), 1);
+        assert!(sixel.matches(";2;").count() <= 128);
+    }
+
+    #[test]
+    fn test_hls_palette_roundtrip() {
+        let colors = [
+            [255, 0, 0],
+            [0, 255, 0],
+            [0, 0, 255],
+            [200, 120, 40],
+            [17, 99, 180],
+            [128, 128, 128],
+            [250, 250, 210],
+        ];
+        let rgba: Vec<u8> = colors.iter().flat_map(|c| [c[0], c[1], c[2], 255].repeat(6)).collect();
+        let opts = EncodeOptions {
+            palette_format: PaletteFormat::Hls,
+            ..Default::default()
+        };
+        let sixel = sixel_encode_impl(&rgba, 6, colors.len(), &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap();
+        assert!(sixel.contains("#0;1;"), "{sixel}");
+        assert!(!sixel.contains(";2;"), "{sixel}");
+
+        let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+        for (row, expected) in colors.iter().enumerate() {
+            let pixel = &decoded.pixels[row * decoded.width * 4..][..3];
+            for (&a, &b) in pixel.iter().zip(expected) {
+                assert!((a as i32 - b as i32).abs() <= 3, "row {row}: {pixel:?} != {expected:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_monochrome() {
+        // Left half black, right half white, plus a 50% gray row block.
+        let (width, height) = (32, 12);
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let v = if i / width >= 6 {
+                    128
+                } else if i % width < 16 {
+                    0
+                } else {
+                    255
+                };
+                [v, v, v, 255]
+            })
+            .collect();
+        let encode = |color_mode, dither| {
+            let opts = EncodeOptions {
+                color_mode,
+                dither,
+                ..Default::default()
+            };
+            sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap()
+        };
+
+        let sixel = encode(ColorMode::Monochrome { invert: false }, Dither::FloydSteinberg);
+        assert!(!sixel.contains('#'), "{sixel}");
+        let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+        let set = |x: usize, y: usize| decoded.pixels[(y * decoded.width + x) * 4 + 3] != 0;
+        assert!((0..6).all(|y| (0..16).all(|x| !set(x, y)) && (16..32).all(|x| set(x, y))));
+        let gray_set = (6..12).flat_map(|y| (0..32).map(move |x| (x, y))).filter(|&(x, y)| set(x, y)).count();
+        assert!((64..=128).contains(&gray_set), "{gray_set} of 192 gray pixels set");
+
+        // Inverted: set bits for dark pixels. Without dithering 50% gray rounds up to light.
+        let inverted = encode(ColorMode::Monochrome { invert: true }, Dither::None);
+        assert_eq!(drawn_pixels(&inverted), 16 * 6);
+    }
+
+    #[test]
+    fn test_printer_framing() {
+        let (width, height) = (1000, 20);
+        let rgba = [0u8, 0, 0, 255].repeat(width * height);
+        let opts = EncodeOptions {
+            color_mode: ColorMode::Monochrome { invert: true },
+            printer: Some(PrinterOptions::default()),
+            ..Default::default()
+        };
+        let sixel = sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap();
+        assert!(sixel.starts_with("\x1bP3;"), "square pixels are sent as 2:1");
+        assert!(sixel.ends_with("\x1b\\\x0c"));
+
+        let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+        assert_eq!(decoded.width, 800);
+        assert_eq!(drawn_pixels(&sixel), 800 * 8, "1000x20 shrinks to 800x16, halved to 8 rows");
+    }
+
+    #[test]
+    fn test_background_color() {
+        // A white page with two boxes and white margins at the right and bottom.
+        let (width, height) = (40, 14);
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| match (i % width, i / width) {
+                (5..10, 2..8) => [200, 0, 0, 255],
+                (12..20, 3..5) => [0, 0, 160, 255],
+                _ => [255, 255, 255, 255],
+            })
+            .collect();
+        let encode = |color_mode, background_color| {
+            let opts = EncodeOptions {
+                color_mode,
+                background_color,
+                ..Default::default()
+            };
+            sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap()
+        };
+
+        let white = Some(Rgb::new(255, 255, 255));
+        for color_mode in [ColorMode::Color, ColorMode::HighColor { bands_per_palette: 1 }] {
+            let sixel = encode(color_mode, white);
+            assert!(sixel.starts_with("\x1bP9;0;0q#0;2;100;100;100"), "{sixel}");
+            assert!(sixel.contains("\"1;1;40;14"), "{sixel}");
+            let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+            let drawn = crate::SixelImage::decode(encode(color_mode, None).as_bytes()).unwrap();
+            assert_eq!((decoded.width, decoded.height), (width, height));
+            // Without raster attributes the last band decodes as 6 full rows.
+            assert_eq!(decoded.pixels, drawn.pixels[..rgba.len()], "{color_mode:?}");
+        }
+        assert!(encode(ColorMode::Color, white).len() < encode(ColorMode::Color, None).len());
+
+        // Plain opaque output keeps its header; only elision adds raster attributes.
+        let opaque = sixel_encode_impl(
+            &rgba,
+            width,
+            height,
+            &EncodeOptions::default(),
+            PixelAspectRatio::Square,
+            BackgroundMode::Opaque,
+        )
+        .unwrap();
+        assert!(opaque.starts_with("\x1bP9;0;0q") && !opaque.contains('"'), "{opaque}");
+    }
+
+    #[test]
+    fn test_resize_too_large() {
+        let rgba = [255u8, 0, 0, 255].repeat(4 * 4);
+        let encode = |resize, pixel_aspect_ratio| {
+            let opts = EncodeOptions {
+                resize: Some(resize),
+                ..Default::default()
+            };
+            sixel_encode_impl(&rgba, 4, 4, &opts, pixel_aspect_ratio, BackgroundMode::Transparent)
+        };
+
+        for (resize, aspect) in [
+            (Resize::Scale(u32::MAX), PixelAspectRatio::Square),
+            (Resize::Scale(u32::MAX), PixelAspectRatio::Ratio2To1),
+            (Resize::Scale(5000), PixelAspectRatio::Square),
+            (Resize::Exact { width: 2_000_000, height: 1 }, PixelAspectRatio::Square),
+            (
+                Resize::Exact {
+                    width: 100_000,
+                    height: 100_000,
+                },
+                PixelAspectRatio::Square,
+            ),
+        ] {
+            assert!(matches!(encode(resize, aspect), Err(SixelError::InvalidDimensions { .. })), "{resize:?}");
+        }
+        assert!(encode(Resize::Scale(4), PixelAspectRatio::Square).is_ok());
+    }
+
+    #[test]
+    fn test_transparent_color() {
+        // A red box on a magenta sprite background with slightly off pixels.
+        let (width, height) = (16, 12);
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| match (i % width, i / width) {
+                (4..12, 3..9) => [200, 0, 0, 255],
+                (0, _) => [250, 3, 252, 255],
+                _ => [255, 0, 255, 255],
+            })
+            .collect();
+        let encode = |resize| {
+            let opts = EncodeOptions {
+                transparent_color: Some((Rgb::new(255, 0, 255), 8)),
+                resize,
+                ..Default::default()
+            };
+            sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap()
+        };
+
+        let sixel = encode(None);
+        assert!(!sixel.contains("#1;"), "only the box takes a register: {sixel}");
+        let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+        for (i, c) in decoded.pixels.chunks_exact(4).enumerate() {
+            let inside = matches!((i % decoded.width, i / decoded.width), (4..12, 3..9));
+            assert_eq!(c[3] != 0, inside, "pixel {i}");
+        }
+
+        // Keyed before resampling: no magenta fringe around the box.
+        let sixel = encode(Some(Resize::Exact { width: 8, height: 6 }));
+        let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+        assert!(decoded.pixels.chunks_exact(4).filter(|c| c[3] != 0).all(|c| c[2] < 64), "{sixel}");
+    }
+
+    #[test]
+    fn test_fixed_colors() {
+        // A colorful gradient with black text pixels; without dithering they
+        // map to the pinned black exactly.
+        let (width, height) = (48, 24);
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| match (i % width, i / width) {
+                (x, 10..14) if x % 3 == 0 => [0, 0, 0, 255],
+                (x, y) => [(x * 5) as u8, (y * 10) as u8, 200 - (x * 4) as u8, 255],
+            })
+            .collect();
+        let fixed_colors = vec![Rgb::new(0, 0, 0), Rgb::new(255, 255, 255), Rgb::new(0x12, 0x34, 0x56)];
+        let encode = |color_mode, max_colors| {
+            let opts = EncodeOptions {
+                max_colors,
+                fixed_colors: fixed_colors.clone(),
+                diffusion: 0.0,
+                color_mode,
+                ..Default::default()
+            };
+            sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap()
+        };
+
+        for color_mode in [ColorMode::Color, ColorMode::HighColor { bands_per_palette: 1 }] {
+            let sixel = encode(color_mode, 8);
+            assert!(sixel.contains("#0;2;0;0;0#1;2;100;100;100#2;2;7;20;34"), "{sixel}");
+            assert!(!sixel.contains("#8;"), "{sixel}");
+            let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+            for (i, c) in decoded.pixels.chunks_exact(4).enumerate() {
+                if rgba[i * 4..i * 4 + 3] == [0, 0, 0] {
+                    assert_eq!(c, [0, 0, 0, 255], "{color_mode:?} pixel {i}");
+                }
+            }
+        }
+
+        // Only the fixed colors fit: everything is dithered onto them.
+        let sixel = encode(ColorMode::Color, 2);
+        assert!(sixel.contains("#0;2;0;0;0#1;2;100;100;100"), "{sixel}");
+        assert!(!sixel.contains("#2;"), "{sixel}");
+    }
+
+    #[test]
+    fn test_weight_map() {
+        // A large green gradient and a small blue "dialog" gradient.
+        let (width, height) = (64, 24);
+        let in_dialog = |i: usize| i % width >= 48 && i / width < 12;
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let v = (i % width * 4) as u8;
+                if in_dialog(i) {
+                    [0, v / 4, 255 - (i / width * 16) as u8, 255]
+                } else {
+                    [v / 3, 255 - v, (i / width * 4) as u8, 255]
+                }
+            })
+            .collect();
+        let dialog_colors = |weight_map: Option<Arc<[u8]>>| {
+            let opts = EncodeOptions {
+                max_colors: 8,
+                diffusion: 0.0,
+                weight_map,
+                ..Default::default()
+            };
+            let sixel = sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent)?;
+            let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+            let mut colors: Vec<&[u8]> = decoded
+                .pixels
+                .chunks_exact(4)
+                .enumerate()
+                .filter(|(i, _)| in_dialog(*i))
+                .map(|(_, c)| c)
+                .collect();
+            colors.sort_unstable();
+            colors.dedup();
+            Ok::<_, SixelError>(colors.len())
+        };
+
+        let weights: Arc<[u8]> = (0..width * height).map(|i| if in_dialog(i) { 255 } else { 4 }).collect();
+        let uniform: Arc<[u8]> = vec![7; width * height].into();
+        let unweighted = dialog_colors(None).unwrap();
+        assert!(dialog_colors(Some(weights)).unwrap() > unweighted);
+        assert_eq!(dialog_colors(Some(uniform)).unwrap(), unweighted);
+        assert!(matches!(
+            dialog_colors(Some(vec![1; 10].into())),
+            Err(SixelError::BufferSizeMismatch { expected: 1536, actual: 10 })
+        ));
+    }
+
+    #[test]
+    fn test_adaptive_diffusion() {
+        // A flat gray between two levels next to a gradient.
+        let (width, height) = (64usize, 12);
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let v = 120 + (i % width).saturating_sub(32) as u8 * 4;
+                [v, v, v, 255]
+            })
+            .collect();
+        let decode_grays = |adaptive_diffusion| {
+            let opts = EncodeOptions {
+                color_mode: ColorMode::Grayscale(GrayLevels::Even(4)),
+                adaptive_diffusion,
+                ..Default::default()
+            };
+            let sixel = sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap();
+            let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+            let distinct = |xs: std::ops::Range<usize>| {
+                let mut grays: Vec<u8> = (0..height)
+                    .flat_map(|y| xs.clone().map(move |x| y * decoded.width + x))
+                    .map(|i| decoded.pixels[i * 4])
+                    .collect();
+                grays.sort_unstable();
+                grays.dedup();
+                grays.len()
+            };
+            (distinct(0..28), distinct(40..64))
+        };
+
+        assert_eq!(decode_grays(false).0, 2, "the flat area is dithered by default");
+        let (flat, gradient) = decode_grays(true);
+        assert_eq!(flat, 1, "the flat area is not dithered");
+        assert!(gradient >= 2, "the gradient is still dithered");
+    }
+
+    #[test]
+    fn test_grayscale() {
+        // Tinted gradient: grayscale output must only define neutral grays.
+        let (width, height) = (64, 6);
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let v = (i % width * 4) as u8;
+                [v, v.saturating_add(20), v / 2, 255]
+            })
+            .collect();
+        for levels in [GrayLevels::Even(4), GrayLevels::Histogram(4), GrayLevels::Even(256)] {
+            let opts = EncodeOptions {
+                color_mode: ColorMode::Grayscale(levels),
+                ..Default::default()
+            };
+            let sixel = sixel_encode_impl(&rgba, width, height, &opts, PixelAspectRatio::Square, BackgroundMode::Transparent).unwrap();
+            let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+            let mut grays: Vec<u8> = decoded
+                .pixels
+                .chunks_exact(4)
+                .filter(|c| c[3] != 0)
+                .map(|c| {
+                    assert!(c[0] == c[1] && c[1] == c[2], "{levels:?}: {c:?} is not gray");
+                    c[0]
+                })
+                .collect();
+            grays.sort_unstable();
+            grays.dedup();
+            if levels == GrayLevels::Even(4) {
+                assert_eq!(grays, [0, 84, 171, 255]);
+            }
+            assert!(grays.len() <= 4 || levels == GrayLevels::Even(256));
+        }
+    }
+
+    #[test]
+    fn test_color_space_dither_brightness() {
+        // Mid gray fully diffused onto black and white: the share of white
+        // pixels follows the gray's position between them in the working space.
+        let (width, height) = (256, 24);
+        // Grays around 128, more than `max_colors`, so the image is quantized.
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| [118 + (i % 5 * 5) as u8; 3].into_iter().chain([255]))
+            .collect();
+        let palette = quantette::PaletteBuf::try_from(vec![Srgb::new(0, 0, 0), Srgb::new(255, 255, 255)]).unwrap();
+        let white_share = |color_space| {
+            let opts = EncodeOptions {
+                max_colors: 2,
+                diffusion: 1.0,
+                quantize_method: palette.clone().into(),
+                color_space,
+                ..Default::default()
+            };
+            let sixel = sixel_encode(&rgba, width, height, &opts).unwrap();
+            let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+            decoded.pixels.chunks_exact(4).filter(|c| c[0] == 255).count() as f32 / (width * height) as f32
+        };
+        // sRGB 128 is about 50% of the way in gamma encoded values, 22% in linear light.
+        assert!((white_share(ColorSpace::Srgb) - 0.5).abs() < 0.02);
+        assert!((white_share(ColorSpace::LinearRgb) - 0.216).abs() < 0.02);
+        assert!((white_share(ColorSpace::Oklab) - 0.6).abs() < 0.02);
+        assert!((white_share(ColorSpace::Lab) - 0.536).abs() < 0.02);
+    }
+
+    #[test]
+    fn test_color_spaces_roundtrip() {
+        let (width, height) = (48, 12);
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| {
+                let (x, y) = (i % width, i / width);
+                [(x * 5) as u8, (y * 20) as u8, 255 - (x * 5) as u8, if x == 0 { 0 } else { 255 }]
+            })
+            .collect();
+        for color_space in [ColorSpace::Srgb, ColorSpace::LinearRgb, ColorSpace::Oklab, ColorSpace::Lab] {
+            for quantize_method in [QuantizeMethod::Wu, QuantizeMethod::kmeans()] {
+                let opts = EncodeOptions {
+                    max_colors: 16,
+                    color_space,
+                    quantize_method,
+                    ..Default::default()
+                };
+                let sixel = sixel_encode(&rgba, width, height, &opts).unwrap();
+                let decoded = crate::SixelImage::decode(sixel.as_bytes()).unwrap();
+                let mut colors: Vec<&[u8]> = decoded.pixels.chunks_exact(4).filter(|c| c[3] != 0).collect();
+                assert_eq!(colors.len(), (width - 1) * height, "{color_space:?}");
+                colors.sort_unstable();
+                colors.dedup();
+                assert!(colors.len() <= 16, "{color_space:?}: {} colors", colors.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_encoder_reuses_buffers() {
+        let frame = |width: usize, height: usize, shift: usize| {
+            let rgba = (0..width * height)
+                .flat_map(|i| {
+                    let (x, y) = (i % width, i / width);
+                    [
+                        (x * 7 + shift) as u8,
+                        (y * 11) as u8,
+                        (x * y + shift) as u8,
+                        if (x + y) % 9 == 0 { 0 } else { 255 },
+                    ]
+                })
+                .collect();
+            crate::SixelImage::from_rgba(rgba, width, height)
+        };
+        let opts = EncodeOptions {
+            max_colors: 32,
+            optimize_size: true,
+            ..Default::default()
+        };
+        let mut encoder = Encoder::new(opts.clone());
+        let first = frame(64, 40, 0);
+        assert_eq!(encoder.encode(&first).unwrap(), first.encode_with(&opts).unwrap());
+        let capacities = |e: &Encoder| {
+            (
+                e.buffers.opacity_mask.words.capacity(),
+                e.buffers.rgb_pixels.capacity(),
+                e.buffers.band.sixels.capacity(),
+                e.buffers.band.colors_used.capacity(),
+                e.buffers.band.out.capacity(),
+            )
+        };
+        let after_first = capacities(&encoder);
+
+        // Frames of the same or a smaller size encode identically without growing the buffers.
+        for (i, (width, height)) in [(64, 40), (50, 33), (1, 1), (64, 17)].into_iter().enumerate() {
+            let image = frame(width, height, i * 13);
+            assert_eq!(encoder.encode(&image).unwrap(), image.encode_with(&opts).unwrap(), "{width}x{height}");
+        }
+        assert_eq!(capacities(&encoder), after_first);
+
+        // Larger frames and option changes still encode correctly.
+        encoder.options_mut().color_mode = ColorMode::Grayscale(GrayLevels::Even(8));
+        let image = frame(80, 50, 5);
+        let expected = image.encode_with(encoder.options()).unwrap();
+        assert_eq!(encoder.encode(&image).unwrap(), expected);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn test_parallel_matches_serial() {
+        let (width, height) = (97, 61);
+        let indices: Vec<u8> = (0..width * height).map(|i| ((i % width / 7 + i / width / 5 + i % 3) % 40) as u8).collect();
+        let mut opacity_mask = BitMask::zeros(width * height);
+        for i in (0..width * height).filter(|i| i % 11 != 0) {
+            opacity_mask.set(i);
+        }
+        let redefinitions = [
+            Redefinition {
+                band: 0,
+                register: 3,
+                color: (2, [1, 2, 3]),
+            },
+            Redefinition {
+                band: 4,
+                register: 7,
+                color: (2, [50, 50, 50]),
+            },
+        ];
+        for optimize_size in [false, true] {
+            for monochrome in [false, true] {
+                let source = BandSource {
+                    indices: &indices,
+                    opacity_mask: &opacity_mask,
+                    width,
+                    height,
+                    bands: height.div_ceil(6),
+                    palette_len: 40,
+                    monochrome,
+                    optimize_size,
+                };
+                let redefinitions = if monochrome { &[][..] } else { &redefinitions[..] };
+                let mut serial = String::new();
+                write_bands(&mut serial, &source, redefinitions, &ProgressHook::default(), &mut BandBuffers::default()).unwrap();
+                let mut parallel = String::new();
+                write_bands_parallel(&mut parallel, &source, redefinitions, &ProgressHook::default()).unwrap();
+                assert_eq!(serial, parallel, "optimize_size: {optimize_size}, monochrome: {monochrome}");
+            }
+        }
+
+        // Pixel preparation only goes parallel with more than one thread.
+        let (width, height) = (300, 230);
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build().unwrap();
+        assert!(pool.install(|| use_threads(width * height)));
+        let rgba: Vec<u8> = (0..width * height)
+            .flat_map(|i| [(i % 251) as u8, (i / width) as u8, 77, (i * 7 % 256) as u8])
+            .collect();
+        let view = RgbaView::whole(&rgba, width, height).unwrap();
+        for alpha in [AlphaMode::Threshold(128), AlphaMode::OrderedDither, AlphaMode::Composite(Rgb::new(10, 20, 30))] {
+            let (mut mask, mut rgb_pixels) = (BitMask::default(), Vec::new());
+            pool.install(|| prepare_pixels(view, alpha, &mut mask, &mut rgb_pixels));
+            for (i, c) in view.pixels().enumerate() {
+                assert_eq!(mask.get(i), is_drawn(alpha, width, i, c), "{alpha:?} pixel {i}");
+                assert_eq!(rgb_pixels[i], pixel_color(alpha, c), "{alpha:?} pixel {i}");
+            }
+        }
+    }
 }
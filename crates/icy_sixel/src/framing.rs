@@ -0,0 +1,242 @@
+//! Transport framing of the encoded SIXEL sequence.
+//!
+//! By default the encoder writes a single 7-bit `ESC P`…`ESC \` sequence on
+//! one line. Terminal multiplexers, 8-bit terminals and serial links need the
+//! same data framed differently; none of this changes the decoded image.
+
+use std::borrow::Cow;
+
+/// Longest DCS string GNU screen passes through in one piece, framing included.
+const SCREEN_PACKET_SIZE: usize = 256;
+
+/// Terminal multiplexer to pass the SIXEL sequence through.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Passthrough {
+    /// tmux: the sequence is wrapped in `ESC Ptmux;`…`ESC \` with every ESC
+    /// doubled. Needs `set -g allow-passthrough on` in tmux 3.3 and later.
+    Tmux,
+    /// GNU screen: the sequence is split into DCS strings short enough for
+    /// screen's string buffer, which screen forwards one by one.
+    Screen,
+}
+
+/// How the SIXEL sequence is framed for transport
+/// (see [`EncodeOptions::framing`](crate::EncodeOptions::framing)).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OutputFraming {
+    /// Wrap the output for a terminal multiplexer (default: `None`).
+    pub passthrough: Option<Passthrough>,
+    /// Use the 8-bit C1 controls DCS (0x90) and ST (0x9C) instead of
+    /// `ESC P` and `ESC \` (default: `false`).
+    ///
+    /// In the returned `String` these are the characters U+0090 and U+009C,
+    /// which `as_bytes()` turns into the two-byte UTF-8 sequences C2 90 and
+    /// C2 9C. That is what UTF-8 terminals expect; for a raw 8-bit link use
+    /// [`SixelImage::encode_bytes`](crate::SixelImage::encode_bytes) or
+    /// [`sixel_bytes`] to get the single bytes 0x90 and 0x9C.
+    pub c1_controls: bool,
+    /// Break the SIXEL data into lines of at most this many characters
+    /// (default: `None`, a single line).
+    ///
+    /// Lines are only broken between commands, never inside a number, so a
+    /// single command longer than the limit keeps its own, longer line.
+    pub line_length: Option<usize>,
+}
+
+impl OutputFraming {
+    /// DCS introducer.
+    pub(crate) fn dcs(&self) -> &'static str {
+        if self.c1_controls {
+            "\u{90}"
+        } else {
+            "\x1bP"
+        }
+    }
+
+    /// String terminator.
+    pub(crate) fn st(&self) -> &'static str {
+        if self.c1_controls {
+            "\u{9c}"
+        } else {
+            "\x1b\\"
+        }
+    }
+
+    /// Apply line wrapping and multiplexer passthrough to an encoded sequence
+    /// whose DCS header (introducer, parameters and `q`) is `header_len` bytes long.
+    pub(crate) fn apply(&self, sixel: String, header_len: usize) -> String {
+        let sixel = match self.line_length {
+            Some(line_length) => wrap_lines(&sixel, header_len, line_length.max(1)),
+            None => sixel,
+        };
+        match self.passthrough {
+            None => sixel,
+            Some(Passthrough::Tmux) => {
+                let mut out = String::with_capacity(sixel.len() + 16);
+                out.push_str("\x1bPtmux;");
+                for c in sixel.chars() {
+                    if c == '\x1b' {
+                        out.push('\x1b');
+                    }
+                    out.push(c);
+                }
+                out.push_str("\x1b\\");
+                out
+            }
+            Some(Passthrough::Screen) => wrap_screen(&sixel),
+        }
+    }
+}
+
+/// Convert an encoded SIXEL string into the bytes of an 8-bit link.
+///
+/// Every character becomes a single byte, so the C1 controls U+0090 and
+/// U+009C go out as 0x90 and 0x9C. Output without C1 controls is plain
+/// ASCII and comes back unchanged.
+pub fn sixel_bytes(sixel: &str) -> Vec<u8> {
+    sixel
+        .chars()
+        .map(|c| {
+            debug_assert!(u32::from(c) <= 0xff, "unexpected character {c:?} in SIXEL output");
+            c as u8
+        })
+        .collect()
+}
+
+/// Break `sixel` into lines of at most `line_length` characters. The header
+/// stays in one piece, and a line never ends inside a parameter list or
+/// between an ESC and the character it introduces.
+fn wrap_lines(sixel: &str, header_len: usize, line_length: usize) -> String {
+    let mut out = String::with_capacity(sixel.len() + sixel.len() / line_length + 1);
+    let mut line = 0;
+    let mut push_token = |out: &mut String, token: &str| {
+        let len = token.chars().count();
+        if line > 0 && line + len > line_length {
+            out.push('\n');
+            line = 0;
+        }
+        out.push_str(token);
+        line += len;
+    };
+
+    let (header, body) = sixel.split_at(header_len);
+    push_token(&mut out, header);
+    let mut rest = body;
+    while let Some(first) = rest.chars().next() {
+        let mut end = first.len_utf8();
+        if first == '\x1b' {
+            end += rest[end..].chars().next().map_or(0, char::len_utf8);
+        }
+        end += rest[end..].bytes().take_while(|b| b.is_ascii_digit() || *b == b';').count();
+        let (token, tail) = rest.split_at(end);
+        push_token(&mut out, token);
+        rest = tail;
+    }
+    out
+}
+
+/// Split `sixel` into DCS strings of at most [`SCREEN_PACKET_SIZE`] bytes.
+///
+/// Every ESC of the inner sequence ends a packet, so screen never mistakes
+/// the inner `ESC \` for the end of its own string; the terminal sees the
+/// packets' contents joined back together.
+fn wrap_screen(sixel: &str) -> String {
+    const DATA_SIZE: usize = SCREEN_PACKET_SIZE - 4;
+    let mut out = String::with_capacity(sixel.len() + sixel.len() / DATA_SIZE * 4 + 16);
+    let mut start = 0;
+    while start < sixel.len() {
+        let mut end = (start + DATA_SIZE).min(sixel.len());
+        while !sixel.is_char_boundary(end) {
+            end -= 1;
+        }
+        if let Some(esc) = sixel[start..end].find('\x1b') {
+            end = start + esc + 1;
+        }
+        out.push_str("\x1bP");
+        out.push_str(&sixel[start..end]);
+        out.push_str("\x1b\\");
+        start = end;
+    }
+    out
+}
+
+/// Unwrap a tmux passthrough sequence (`ESC Ptmux;` with doubled ESCs).
+///
+/// Returns the data unchanged if it contains no tmux wrapper.
+pub(crate) fn unwrap_tmux(data: &[u8]) -> Cow<'_, [u8]> {
+    const PREFIX: &[u8] = b"\x1bPtmux;";
+    let Some(start) = data.windows(PREFIX.len()).position(|w| w == PREFIX) else {
+        return Cow::Borrowed(data);
+    };
+
+    let mut out = Vec::with_capacity(data.len());
+    let mut idx = start + PREFIX.len();
+    while idx < data.len() {
+        match (data[idx], data.get(idx + 1)) {
+            (0x1b, Some(0x1b)) => {
+                out.push(0x1b);
+                idx += 2;
+            }
+            // A single ESC \ ends the tmux wrapper.
+            (0x1b, Some(b'\\')) => break,
+            (byte, _) => {
+                out.push(byte);
+                idx += 1;
+            }
+        }
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_lines() {
+        let sixel = "\x1bP0;1;0q\"1;1;40;6#0;2;100;0;0#1;2;0;100;0#0!40~$#1!12?-\x1b\\";
+        let wrapped = wrap_lines(sixel, 8, 12);
+        assert_eq!(wrapped.replace('\n', ""), sixel);
+        for line in wrapped.lines() {
+            assert!(line.len() <= 12, "{line:?}");
+            assert!(!line.starts_with(|c: char| c.is_ascii_digit() || c == ';' || c == '\\'), "{line:?}");
+        }
+        assert!(wrapped.starts_with("\x1bP0;1;0q\n"));
+    }
+
+    #[test]
+    fn test_screen_packets() {
+        let sixel = format!("\x1bPq{}\x1b\\", "~".repeat(1000));
+        let wrapped = wrap_screen(&sixel);
+        let packets: Vec<&str> = wrapped.split_terminator("\x1b\\").collect();
+        assert!(packets.iter().all(|p| p.starts_with("\x1bP") && p.len() + 2 <= SCREEN_PACKET_SIZE));
+        // The inner ESCs end their packets; the contents join back into the original.
+        let joined: String = packets.iter().map(|p| &p[2..]).collect();
+        assert_eq!(joined, sixel);
+    }
+
+    #[test]
+    fn test_sixel_bytes() {
+        let framing = OutputFraming {
+            c1_controls: true,
+            ..Default::default()
+        };
+        let sixel = format!("{}q#0~{}", framing.dcs(), framing.st());
+        assert_eq!(sixel.as_bytes(), b"\xc2\x90q#0~\xc2\x9c");
+        assert_eq!(sixel_bytes(&sixel), b"\x90q#0~\x9c");
+        assert_eq!(sixel_bytes("\x1bPq#0~\x1b\\"), b"\x1bPq#0~\x1b\\");
+    }
+
+    #[test]
+    fn test_tmux_roundtrip() {
+        let sixel = "\x1bPq#0;2;100;0;0#0~~\x1b\\".to_string();
+        let framing = OutputFraming {
+            passthrough: Some(Passthrough::Tmux),
+            ..Default::default()
+        };
+        let wrapped = framing.apply(sixel.clone(), 3);
+        assert_eq!(wrapped, "\x1bPtmux;\x1b\x1bPq#0;2;100;0;0#0~~\x1b\x1b\\\x1b\\");
+        assert_eq!(unwrap_tmux(wrapped.as_bytes()), sixel.as_bytes());
+        assert!(matches!(unwrap_tmux(sixel.as_bytes()), Cow::Borrowed(_)));
+    }
+}
@@ -0,0 +1,195 @@
+//! Grayscale conversion and gray level selection for the grayscale encode mode.
+//!
+//! Pixels are reduced to their luminance, computed from linear light with
+//! Rec. 709 weights and encoded back with the sRGB transfer curve, so equal
+//! steps between gray levels look roughly equally large.
+
+use quantette::deps::palette::Srgb;
+
+use crate::{
+    color_space::{linear_to_srgb, srgb_to_linear},
+    dither::{dither_to_indices, Dither},
+};
+
+/// How the gray levels of [`ColorMode::Grayscale`](crate::ColorMode::Grayscale)
+/// are chosen. The level count is clamped to 2-256.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GrayLevels {
+    /// Levels evenly spaced from black to white.
+    Even(u16),
+    /// Levels placed optimally for the image's luminance histogram, which
+    /// spends them where most pixels are.
+    Histogram(u16),
+}
+
+impl GrayLevels {
+    fn count(self) -> usize {
+        match self {
+            Self::Even(n) | Self::Histogram(n) => n.clamp(2, 256) as usize,
+        }
+    }
+}
+
+impl Default for GrayLevels {
+    fn default() -> Self {
+        Self::Even(256)
+    }
+}
+
+/// Perceptual luminance of `c` in the range 0.0-1.0.
+pub(crate) fn luminance(c: Srgb<u8>) -> f32 {
+    let y = 0.2126 * srgb_to_linear(c.red) + 0.7152 * srgb_to_linear(c.green) + 0.0722 * srgb_to_linear(c.blue);
+    linear_to_srgb(y)
+}
+
+/// Reduce the drawn pixels to gray levels.
+///
+/// Returns the gray value of every level and the level index of every pixel
+/// (0 for undrawn pixels).
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn quantize_gray(
+    rgb_pixels: &[Srgb<u8>],
+    width: usize,
+    levels: GrayLevels,
+    dither: Dither,
+    diffusion: f32,
+    local_strength: Option<&[f32]>,
+    serpentine: bool,
+    drawn: impl Fn(usize) -> bool,
+) -> (Vec<u8>, Vec<u8>) {
+    let gray: Vec<f32> = rgb_pixels.iter().map(|&c| luminance(c)).collect();
+
+    let grays = match levels {
+        GrayLevels::Even(_) => {
+            let n = levels.count();
+            (0..n).map(|i| ((i * 255 + (n - 1) / 2) / (n - 1)) as u8).collect()
+        }
+        GrayLevels::Histogram(_) => {
+            let mut histogram = [0u64; 256];
+            for (i, &v) in gray.iter().enumerate() {
+                if drawn(i) {
+                    histogram[(v * 255.0).round() as usize] += 1;
+                }
+            }
+            histogram_levels(&histogram, levels.count())
+        }
+    };
+
+    // Levels that the terminal can't tell apart are merged up front, so the
+    // dither works with the grays that are actually displayed.
+    let mut grays: Vec<u8> = grays.into_iter().map(snap_to_percent).collect();
+    grays.dedup();
+
+    let pixels: Vec<[f32; 3]> = gray.iter().map(|&v| [v; 3]).collect();
+    let palette: Vec<[f32; 3]> = grays.iter().map(|&g| [g as f32 / 255.0; 3]).collect();
+    let indices = dither_to_indices(&pixels, width, &palette, dither, diffusion, local_strength, serpentine, drawn);
+    (grays, indices)
+}
+
+/// Round a gray value to the nearest value a SIXEL percent definition can express.
+fn snap_to_percent(g: u8) -> u8 {
+    let percent = (g as u32 * 100 + 127) / 255;
+    ((percent * 255 + 50) / 100) as u8
+}
+
+/// Choose up to `n` gray levels minimizing the squared error over `histogram`.
+///
+/// One dimensional k-means has an exact solution: the optimal clusters are
+/// contiguous ranges of the histogram, found by dynamic programming.
+fn histogram_levels(histogram: &[u64; 256], n: usize) -> Vec<u8> {
+    let used: Vec<usize> = (0..256).filter(|&v| histogram[v] > 0).collect();
+    if used.len() <= n {
+        return if used.is_empty() { vec![0] } else { used.iter().map(|&v| v as u8).collect() };
+    }
+
+    // Prefix sums of weight, weight * value and weight * value² over the used bins
+    let len = used.len();
+    let mut count = vec![0.0f64; len + 1];
+    let mut sum = vec![0.0f64; len + 1];
+    let mut sum_sq = vec![0.0f64; len + 1];
+    for (i, &v) in used.iter().enumerate() {
+        let w = histogram[v] as f64;
+        count[i + 1] = count[i] + w;
+        sum[i + 1] = sum[i] + w * v as f64;
+        sum_sq[i + 1] = sum_sq[i] + w * (v * v) as f64;
+    }
+    // Squared error of merging bins start..end into their weighted mean
+    let cost = |start: usize, end: usize| {
+        let (c, s) = (count[end] - count[start], sum[end] - sum[start]);
+        sum_sq[end] - sum_sq[start] - s * s / c
+    };
+
+    // best[k][end]: lowest error covering the first `end` bins with k + 1 levels
+    let mut best = vec![vec![f64::INFINITY; len + 1]; n];
+    let mut split = vec![vec![0usize; len + 1]; n];
+    for (end, error) in best[0].iter_mut().enumerate().skip(1) {
+        *error = cost(0, end);
+    }
+    for k in 1..n {
+        for end in k + 1..=len {
+            for start in k..end {
+                let error = best[k - 1][start] + cost(start, end);
+                if error < best[k][end] {
+                    best[k][end] = error;
+                    split[k][end] = start;
+                }
+            }
+        }
+    }
+
+    let mut levels = Vec::with_capacity(n);
+    let mut end = len;
+    for k in (0..n).rev() {
+        let start = if k == 0 { 0 } else { split[k][end] };
+        let mean = (sum[end] - sum[start]) / (count[end] - count[start]);
+        levels.push(mean.round() as u8);
+        end = start;
+    }
+    levels.reverse();
+    levels
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_luminance() {
+        assert_eq!(luminance(Srgb::new(0, 0, 0)), 0.0);
+        assert!((luminance(Srgb::new(255, 255, 255)) - 1.0).abs() < 1e-5);
+        assert!((luminance(Srgb::new(128, 128, 128)) - 128.0 / 255.0).abs() < 1e-3);
+        // Green carries most of the luminance, blue the least.
+        assert!(luminance(Srgb::new(0, 255, 0)) > luminance(Srgb::new(255, 0, 0)));
+        assert!(luminance(Srgb::new(255, 0, 0)) > luminance(Srgb::new(0, 0, 255)));
+    }
+
+    #[test]
+    fn test_histogram_levels() {
+        let mut histogram = [0u64; 256];
+        histogram[10] = 5;
+        histogram[200] = 5;
+        assert_eq!(histogram_levels(&histogram, 4), [10, 200]);
+
+        // Two clusters, two levels: each level lands on its cluster's mean.
+        histogram[12] = 5;
+        histogram[202] = 5;
+        assert_eq!(histogram_levels(&histogram, 2), [11, 201]);
+
+        // Levels go where the pixels are.
+        let mut histogram = [0u64; 256];
+        histogram[..64].fill(100);
+        histogram[64..].fill(1);
+        let levels = histogram_levels(&histogram, 8);
+        assert!(levels.iter().filter(|&&g| g < 64).count() >= 5, "{levels:?}");
+    }
+
+    #[test]
+    fn test_even_levels() {
+        let pixels: Vec<Srgb<u8>> = (0..=255).map(|v| Srgb::new(v, v, v)).collect();
+        let (grays, indices) = quantize_gray(&pixels, 256, GrayLevels::Even(5), Dither::None, 0.0, None, true, |_| true);
+        assert_eq!(grays, [0, 64, 128, 191, 255]);
+        assert_eq!(indices[0], 0);
+        assert_eq!(indices[255], 4);
+        assert!(indices.windows(2).all(|w| w[0] <= w[1]));
+    }
+}
@@ -0,0 +1,171 @@
+//! More than 256 colors through per-band palettes.
+//!
+//! A SIXEL stream may redefine a color register at any point; pixels drawn
+//! before keep their color on most current terminals.
+//! [`ColorMode::HighColor`](crate::ColorMode::HighColor) quantizes every
+//! group of bands on its own and rewrites the registers whose colors changed
+//! before the group's first band.
+
+use std::collections::HashMap;
+
+use quantette::deps::palette::Srgb;
+
+use crate::{
+    encoder::{color_palette, sixel_color, BitMask, EncodeOptions, SixelColor},
+    Result,
+};
+
+/// Color register definition sent before a band.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Redefinition {
+    pub(crate) band: usize,
+    pub(crate) register: usize,
+    pub(crate) color: SixelColor,
+}
+
+/// The result of [`SixelImage::encode_high_color`](crate::SixelImage::encode_high_color).
+#[derive(Clone, Debug)]
+pub struct HighColorEncoding {
+    /// The encoded SIXEL string.
+    pub sixel: String,
+    /// Size in bytes of the same image encoded with a single palette.
+    pub single_palette_bytes: usize,
+}
+
+impl HighColorEncoding {
+    /// Size of the high color stream relative to the single palette one,
+    /// e.g. `1.25` for 25% more bytes.
+    pub fn size_ratio(&self) -> f64 {
+        self.sixel.len() as f64 / self.single_palette_bytes.max(1) as f64
+    }
+}
+
+/// Quantize each group of `bands_per_palette` bands separately and assign
+/// the palette colors to `max_colors` registers.
+///
+/// Returns the register of every pixel and the register definitions to send
+/// before each group, ordered by band.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn band_palettes(
+    rgb_pixels: &[Srgb<u8>],
+    opacity_mask: &BitMask,
+    weights: Option<&[u8]>,
+    width: usize,
+    height: usize,
+    opts: &EncodeOptions,
+    bands_per_palette: usize,
+    max_colors: u16,
+) -> Result<(Vec<u8>, Vec<Redefinition>)> {
+    let rows_per_group = 6 * bands_per_palette.max(1);
+    let register_count = max_colors as usize;
+
+    // Current color of every register, the register holding each color and
+    // the group a register was last used in (0 = never).
+    let mut registers: Vec<Option<SixelColor>> = vec![None; register_count];
+    let mut lookup: HashMap<SixelColor, usize> = HashMap::new();
+    let mut last_used = vec![0usize; register_count];
+
+    let mut indices = vec![0u8; rgb_pixels.len()];
+    let mut redefinitions = Vec::new();
+    for (group, y0) in (0..height).step_by(rows_per_group).enumerate() {
+        let rows = rows_per_group.min(height - y0);
+        let range = y0 * width..(y0 + rows) * width;
+        let mut group_mask = BitMask::zeros(range.len());
+        for (i, pixel) in range.clone().enumerate() {
+            if opacity_mask.get(pixel) {
+                group_mask.set(i);
+            }
+        }
+        let (palette, group_indices) = color_palette(
+            &rgb_pixels[range.clone()],
+            &group_mask,
+            weights.map(|weights| &weights[range.clone()]),
+            width,
+            rows,
+            opts,
+            max_colors,
+        )?;
+        let colors: Vec<SixelColor> = palette.iter().map(|&c| sixel_color(c, opts.palette_format)).collect();
+
+        // Keep registers that already hold a color of this palette, then
+        // overwrite the ones unused the longest, never defined ones first.
+        let mut remap: Vec<Option<usize>> = colors.iter().map(|color| lookup.get(color).copied()).collect();
+        let mut claimed = vec![false; register_count];
+        for &register in remap.iter().flatten() {
+            claimed[register] = true;
+        }
+        let mut free: Vec<usize> = (0..register_count).filter(|&r| !claimed[r]).collect();
+        free.sort_by_key(|&r| (registers[r].is_some(), last_used[r]));
+        let mut free = free.into_iter();
+
+        for (slot, &color) in remap.iter_mut().zip(&colors) {
+            let register = match *slot {
+                Some(register) => register,
+                None => {
+                    let register = free.next().expect("a palette never has more colors than registers");
+                    if let Some(old) = registers[register].replace(color) {
+                        lookup.remove(&old);
+                    }
+                    lookup.insert(color, register);
+                    redefinitions.push(Redefinition { band: y0 / 6, register, color });
+                    register
+                }
+            };
+            last_used[register] = group + 1;
+            *slot = Some(register);
+        }
+
+        for (i, &index) in group_indices.iter().enumerate() {
+            if group_mask.get(i) {
+                indices[range.start + i] = remap[index as usize].unwrap_or_default() as u8;
+            }
+        }
+    }
+    Ok((indices, redefinitions))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ColorMode, EncodeOptions, SixelImage};
+
+    fn distinct_colors(pixels: &[u8]) -> usize {
+        let mut colors: Vec<&[u8]> = pixels.chunks_exact(4).collect();
+        colors.sort_unstable();
+        colors.dedup();
+        colors.len()
+    }
+
+    #[test]
+    fn test_more_colors_than_registers() {
+        let (width, height) = (32, 48);
+        let pixels: Vec<u8> = (0..width * height)
+            .flat_map(|i| [(i % width * 8) as u8, (i / width * 5) as u8, 255 - (i / width * 5) as u8, 255])
+            .collect();
+        let image = SixelImage::from_rgba(pixels, width, height);
+        let opts = EncodeOptions {
+            max_colors: 8,
+            diffusion: 0.0,
+            ..Default::default()
+        };
+
+        let encoded = image.encode_high_color(1, &opts).unwrap();
+        let decoded = SixelImage::decode(encoded.sixel.as_bytes()).unwrap();
+        assert_eq!((decoded.width, decoded.height), (width, height));
+        assert!(distinct_colors(&decoded.pixels) > 8 * 4, "{} colors", distinct_colors(&decoded.pixels));
+        for band in decoded.pixels.chunks(width * 6 * 4) {
+            assert!(distinct_colors(band) <= 8);
+        }
+        assert_eq!(encoded.single_palette_bytes, image.encode_with(&opts).unwrap().len());
+        assert!(encoded.size_ratio() > 1.0);
+
+        // Identical bands keep their registers: nothing is redefined after the first band.
+        let stripes: Vec<u8> = (0..width * height).flat_map(|i| [(i % width * 8) as u8, 40, 90, 255]).collect();
+        let image = SixelImage::from_rgba(stripes, width, height);
+        let opts = EncodeOptions {
+            color_mode: ColorMode::HighColor { bands_per_palette: 2 },
+            ..opts
+        };
+        let sixel = image.encode_with(&opts).unwrap();
+        assert_eq!(sixel.matches(";2;").count(), 8);
+    }
+}
@@ -0,0 +1,145 @@
+//! The `IndexedImage` type for encoding paletted images.
+
+use crate::{
+    encoder::{sixel_encode_indexed, EncodeOptions, Rgb},
+    BackgroundMode, PixelAspectRatio, Result, SixelError, SIXEL_PALETTE_MAX,
+};
+
+/// A paletted image: up to 256 colors and one palette index per pixel.
+///
+/// GIF frames, indexed framebuffers or the output of another quantizer can
+/// be encoded as is. The palette becomes the SIXEL color registers directly,
+/// so colors are never quantized again.
+#[derive(Debug, Clone)]
+pub struct IndexedImage {
+    /// Palette colors (1-256 entries)
+    pub palette: Vec<Rgb>,
+    /// One palette index per pixel, row by row
+    pub indices: Vec<u8>,
+    /// Image width in pixels
+    pub width: usize,
+    /// Image height in pixels
+    pub height: usize,
+    /// Pixels with this palette index are left undrawn
+    pub transparent_index: Option<u8>,
+    /// Pixel aspect ratio for encoding
+    pub aspect_ratio: PixelAspectRatio,
+    /// Background mode for encoding (P2)
+    pub background_mode: BackgroundMode,
+}
+
+impl IndexedImage {
+    /// Creates a new `IndexedImage` from a palette and per-pixel indices.
+    ///
+    /// # Example
+    /// ```rust
+    /// use icy_sixel::{IndexedImage, Rgb};
+    ///
+    /// let palette = vec![Rgb::new(255, 0, 0), Rgb::new(0, 0, 255)];
+    /// let image = IndexedImage::from_indexed(palette, vec![0, 1, 1, 0], 2, 2, None);
+    /// ```
+    pub fn from_indexed(palette: Vec<Rgb>, indices: Vec<u8>, width: usize, height: usize, transparent_index: Option<u8>) -> Self {
+        Self {
+            palette,
+            indices,
+            width,
+            height,
+            transparent_index,
+            aspect_ratio: PixelAspectRatio::default(),
+            background_mode: BackgroundMode::default(),
+        }
+    }
+
+    /// Creates a new `IndexedImage`, validating dimensions, buffer size and palette.
+    ///
+    /// This is a fallible variant of [`IndexedImage::from_indexed`]. It returns an error if:
+    /// - `width == 0` or `height == 0` ([`SixelError::InvalidDimensions`])
+    /// - `indices.len() != width * height` ([`SixelError::BufferSizeMismatch`])
+    /// - the palette is empty or has more than 256 colors ([`SixelError::InvalidPalette`])
+    /// - an index other than `transparent_index` is outside the palette ([`SixelError::InvalidPalette`])
+    pub fn try_from_indexed(palette: Vec<Rgb>, indices: Vec<u8>, width: usize, height: usize, transparent_index: Option<u8>) -> Result<Self> {
+        let image = Self::from_indexed(palette, indices, width, height, transparent_index);
+        image.validate()?;
+        Ok(image)
+    }
+
+    /// Sets the pixel aspect ratio for encoding.
+    #[must_use]
+    pub fn with_aspect_ratio(mut self, aspect_ratio: PixelAspectRatio) -> Self {
+        self.aspect_ratio = aspect_ratio;
+        self
+    }
+
+    /// Sets the background mode for encoding.
+    #[must_use]
+    pub fn with_background_mode(mut self, background_mode: BackgroundMode) -> Self {
+        self.background_mode = background_mode;
+        self
+    }
+
+    /// Encodes this image to a SIXEL string with default options.
+    ///
+    /// # Example
+    /// ```rust
+    /// use icy_sixel::{IndexedImage, Rgb};
+    ///
+    /// let palette = vec![Rgb::new(255, 0, 0), Rgb::new(0, 0, 0)];
+    /// let image = IndexedImage::from_indexed(palette, vec![0, 1, 1, 0], 2, 2, Some(1));
+    /// let sixel = image.encode()?;
+    /// # Ok::<(), icy_sixel::SixelError>(())
+    /// ```
+    #[must_use = "this returns the encoded SIXEL string"]
+    pub fn encode(&self) -> Result<String> {
+        self.encode_with(&EncodeOptions::default())
+    }
+
+    /// Encodes this image to a SIXEL string with custom options.
+    ///
+    /// Only the options that shape the SIXEL stream apply: `optimize_size`,
+    /// `palette_format`, `framing`, `progress` and the form feed of
+    /// `printer`. There is no quantization, dithering, alpha handling or
+    /// resizing; `color_mode` is ignored as well. So are the printer's 2:1
+    /// pixels and page width: size the image for the page beforehand and set
+    /// [`PixelAspectRatio::Ratio2To1`] with [`IndexedImage::with_aspect_ratio`].
+    #[must_use = "this returns the encoded SIXEL string"]
+    pub fn encode_with(&self, opts: &EncodeOptions) -> Result<String> {
+        self.validate()?;
+        sixel_encode_indexed(
+            &self.palette,
+            &self.indices,
+            self.transparent_index,
+            self.width,
+            self.height,
+            opts,
+            self.aspect_ratio,
+            self.background_mode,
+        )
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.width == 0 || self.height == 0 {
+            return Err(SixelError::InvalidDimensions {
+                width: self.width,
+                height: self.height,
+            });
+        }
+        let expected = self.width.checked_mul(self.height).ok_or(SixelError::IntegerOverflow)?;
+        if self.indices.len() != expected {
+            return Err(SixelError::BufferSizeMismatch {
+                expected,
+                actual: self.indices.len(),
+            });
+        }
+        if self.palette.is_empty() || self.palette.len() > SIXEL_PALETTE_MAX {
+            return Err(SixelError::InvalidPalette(format!("palette has {} colors, expected 1-256", self.palette.len())));
+        }
+        let out_of_range = |&&index: &&u8| index as usize >= self.palette.len() && Some(index) != self.transparent_index;
+        if let Some(&index) = self.indices.iter().find(out_of_range) {
+            return Err(SixelError::InvalidPalette(format!(
+                "palette index {index} out of range for {} colors",
+                self.palette.len()
+            )));
+        }
+        Ok(())
+    }
+}
@@ -34,20 +34,42 @@
 
 use thiserror::Error;
 
+mod band_simd;
+pub mod budget;
+pub mod color_space;
 pub mod decoder;
+pub mod dither;
 pub mod encoder;
+pub mod framing;
+pub mod grayscale;
+pub mod high_color;
+pub mod indexed_image;
+pub mod progress;
+pub mod resize;
 pub mod sixel_image;
 
+pub use budget::{BudgetedEncoding, ByteBudget};
+pub use color_space::ColorSpace;
 pub use decoder::DcsSettings;
 #[allow(deprecated)]
 pub use decoder::{sixel_decode, sixel_decode_from_dcs};
+pub use dither::Dither;
 #[allow(deprecated)]
 pub use encoder::{sixel_encode, sixel_encode_default};
-pub use encoder::{EncodeOptions, QuantizeMethod};
-pub use sixel_image::{BackgroundMode, PixelAspectRatio, SixelImage};
+pub use encoder::{AlphaMode, ColorMode, EncodeOptions, Encoder, KmeansOptions, PaletteFormat, PrinterOptions, QuantizeMethod, Rgb};
+pub use framing::{sixel_bytes, OutputFraming, Passthrough};
+pub use grayscale::GrayLevels;
+pub use high_color::HighColorEncoding;
+pub use indexed_image::IndexedImage;
+pub use progress::{EncodeProgress, EncodeStage, ProgressHook};
+pub use resize::{Resize, ResizeFilter};
+pub use sixel_image::{BackgroundMode, CellPlacement, PixelAspectRatio, Region, SixelImage};
 
 /// Errors that can occur during SIXEL encoding or decoding.
+///
+/// New variants may be added in minor releases, so matches need a wildcard arm.
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum SixelError {
     /// Invalid image dimensions (width or height is zero or too large)
     #[error("invalid dimensions: {width}x{height}")]
@@ -69,6 +91,26 @@ pub enum SixelError {
     #[error("quantization error: {0}")]
     Quantization(String),
 
+    /// Region lies (partly) outside the image or is empty
+    #[error("invalid region: {width}x{height} at ({x}, {y})")]
+    InvalidRegion { x: usize, y: usize, width: usize, height: usize },
+
+    /// Encoder input with an invalid palette: wrong size or indices past its end
+    #[error("invalid palette: {0}")]
+    InvalidPalette(String),
+
+    /// The requested options can't be combined
+    #[error("unsupported options: {0}")]
+    UnsupportedOptions(&'static str),
+
+    /// The image can't be encoded within the byte budget
+    #[error("image does not fit into {max_bytes} bytes")]
+    BudgetExceeded { max_bytes: usize },
+
+    /// The encode was cancelled through its [`ProgressHook`]
+    #[error("encoding cancelled")]
+    Cancelled,
+
     /// Integer overflow during processing
     #[error("integer overflow")]
     IntegerOverflow,
@@ -81,3 +123,5 @@ pub type Result<T> = core::result::Result<T, SixelError>;
 pub(crate) const SIXEL_PALETTE_MAX: usize = 256;
 pub(crate) const SIXEL_WIDTH_LIMIT: usize = 1000000;
 pub(crate) const SIXEL_HEIGHT_LIMIT: usize = 1000000;
+/// Max 256 MB of pixel data (64 million pixels * 4 bytes)
+pub(crate) const SIXEL_PIXEL_LIMIT: usize = 64 * 1024 * 1024;
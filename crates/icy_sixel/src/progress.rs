@@ -0,0 +1,133 @@
+//! Progress reporting and cancellation of long encodes.
+//!
+//! Quantizing and dithering a large photo can take seconds. A [`ProgressHook`]
+//! is called between the steps of the pipeline and before every band, and
+//! aborts the encode with [`SixelError::Cancelled`] once its flag is set.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use crate::{Result, SixelError};
+
+/// Step of the encoding pipeline reported by [`ProgressHook`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncodeStage {
+    /// Resampling the image ([`EncodeOptions::resize`](crate::EncodeOptions::resize)).
+    Resize,
+    /// Computing the palette. quantette maps fully opaque images with plain
+    /// Floyd-Steinberg in the same step, so no [`Dither`](Self::Dither)
+    /// stage follows.
+    Quantize,
+    /// Mapping the pixels to the palette, with dithering.
+    Dither,
+    /// Writing the SIXEL bands.
+    Bands,
+}
+
+/// A progress report of [`ProgressHook`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EncodeProgress {
+    /// The step that is about to run or running.
+    pub stage: EncodeStage,
+    /// Finished units of the step: bands for [`EncodeStage::Bands`], 0 or 1
+    /// for the other steps.
+    pub done: usize,
+    /// Total units of the step.
+    pub total: usize,
+}
+
+type ProgressCallback = dyn Fn(EncodeProgress) + Send + Sync;
+
+/// Progress callback and cancellation flag of an encode
+/// (see [`EncodeOptions::progress`](crate::EncodeOptions::progress)).
+///
+/// The default hook reports nothing and never cancels. Both parts are shared
+/// by clones of the hook, so the flag can be set from another thread while
+/// the encode runs.
+///
+/// # Example
+/// ```rust
+/// use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+/// use icy_sixel::{EncodeOptions, ProgressHook, SixelError, SixelImage};
+///
+/// let cancel = Arc::new(AtomicBool::new(false));
+/// let flag = cancel.clone();
+/// let progress = ProgressHook::new()
+///     .with_cancel_flag(cancel)
+///     .with_callback(move |p| {
+///         println!("{:?} {}/{}", p.stage, p.done, p.total);
+///         flag.store(true, Ordering::Relaxed); // e.g. the user pressed "Cancel"
+///     });
+/// let mut opts = EncodeOptions::default();
+/// opts.progress = progress;
+///
+/// let image = SixelImage::from_rgba(vec![255; 64 * 64 * 4], 64, 64);
+/// assert!(matches!(image.encode_with(&opts), Err(SixelError::Cancelled)));
+/// ```
+#[derive(Clone, Default)]
+pub struct ProgressHook {
+    cancel: Option<Arc<AtomicBool>>,
+    callback: Option<Arc<ProgressCallback>>,
+}
+
+impl ProgressHook {
+    /// A hook that reports nothing and never cancels.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancel the encode once `flag` is set.
+    #[must_use]
+    pub fn with_cancel_flag(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.cancel = Some(flag);
+        self
+    }
+
+    /// Call `callback` between the steps of the pipeline and before every band.
+    ///
+    /// The callback runs on the encoding thread and should return quickly.
+    #[must_use]
+    pub fn with_callback(mut self, callback: impl Fn(EncodeProgress) + Send + Sync + 'static) -> Self {
+        self.callback = Some(Arc::new(callback));
+        self
+    }
+
+    /// Whether the cancel flag is set.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.as_ref().is_some_and(|flag| flag.load(Ordering::Relaxed))
+    }
+
+    /// A hook with the same cancel flag that reports nothing.
+    pub(crate) fn silent(&self) -> Self {
+        Self {
+            cancel: self.cancel.clone(),
+            callback: None,
+        }
+    }
+
+    /// Report `done` of `total` units of `stage`, then fail with
+    /// [`SixelError::Cancelled`] if the flag is set.
+    pub(crate) fn report(&self, stage: EncodeStage, done: usize, total: usize) -> Result<()> {
+        if let Some(callback) = &self.callback {
+            callback(EncodeProgress { stage, done, total });
+        }
+        if self.is_cancelled() {
+            return Err(SixelError::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProgressHook")
+            .field("cancel", &self.cancel)
+            .field("callback", &self.callback.as_ref().map(|_| "<callback>"))
+            .finish()
+    }
+}
@@ -0,0 +1,282 @@
+//! Image resampling applied before encoding.
+//!
+//! Resizing works on premultiplied alpha so transparent pixels don't bleed
+//! their (often arbitrary) color into visible neighbors.
+
+use crate::{encoder::RgbaView, PixelAspectRatio};
+
+/// Resampling filter used when resizing an image.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizeFilter {
+    /// Nearest neighbor: keeps hard pixel edges, best for pixel art.
+    Nearest,
+    /// Bilinear (triangle) filter.
+    Bilinear,
+    /// Box filter: averages the covered source pixels when downscaling.
+    Box,
+    /// Lanczos with 3 lobes (default): sharp, high quality downscaling.
+    #[default]
+    Lanczos3,
+}
+
+impl ResizeFilter {
+    /// Radius of the filter kernel in source pixels (at scale 1).
+    fn support(self) -> f32 {
+        match self {
+            Self::Nearest => 0.0,
+            Self::Box => 0.5,
+            Self::Bilinear => 1.0,
+            Self::Lanczos3 => 3.0,
+        }
+    }
+
+    fn kernel(self, x: f32) -> f32 {
+        match self {
+            Self::Nearest => 1.0,
+            Self::Box => {
+                if (-0.5..0.5).contains(&x) {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+            Self::Bilinear => (1.0 - x.abs()).max(0.0),
+            Self::Lanczos3 => {
+                if x.abs() < 3.0 {
+                    sinc(x) * sinc(x / 3.0)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+/// How the image is resized before encoding.
+///
+/// Sizes are given in square (display) pixels. For a non-square
+/// [`PixelAspectRatio`] the image height is then scaled down by the pixel
+/// aspect so that the image keeps its proportions on screen, e.g. a
+/// [`PixelAspectRatio::Ratio2To1`] encode has half as many rows.
+/// `Resize::Scale(1)` applies only that correction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Resize {
+    /// Resize to exactly this size, ignoring the image's aspect ratio.
+    Exact {
+        /// Target width in pixels.
+        width: usize,
+        /// Target height in display pixels.
+        height: usize,
+    },
+    /// Shrink to fit within this box, preserving the aspect ratio.
+    /// Images that already fit keep their size.
+    Fit {
+        /// Maximum width in pixels.
+        max_width: usize,
+        /// Maximum height in display pixels.
+        max_height: usize,
+    },
+    /// Scale both dimensions by an integer factor (for pixel art, usually
+    /// combined with [`ResizeFilter::Nearest`]).
+    Scale(u32),
+}
+
+impl Resize {
+    /// Returns the encoded size in sixel pixels for a `width` x `height` image.
+    pub fn target_size(self, width: usize, height: usize, aspect_ratio: PixelAspectRatio) -> (usize, usize) {
+        let (display_width, display_height) = match self {
+            Self::Exact { width, height } => (width, height),
+            Self::Fit { max_width, max_height } => {
+                let scale = f64::min(max_width as f64 / width as f64, max_height as f64 / height as f64);
+                if scale >= 1.0 {
+                    (width, height)
+                } else {
+                    ((width as f64 * scale).round() as usize, (height as f64 * scale).round() as usize)
+                }
+            }
+            Self::Scale(factor) => (width.saturating_mul(factor as usize), height.saturating_mul(factor as usize)),
+        };
+        let rows = display_height
+            .saturating_mul(aspect_ratio.pan() as usize)
+            .saturating_add(aspect_ratio.pad() as usize / 2)
+            / aspect_ratio.pad() as usize;
+        (display_width.max(1), rows.max(1))
+    }
+}
+
+/// Resample RGBA pixels to `new_width` x `new_height` pixels.
+pub(crate) fn resize_rgba(view: RgbaView<'_>, new_width: usize, new_height: usize, filter: ResizeFilter) -> Vec<u8> {
+    let (width, height) = (view.width, view.height);
+    let premultiplied: Vec<[f32; 4]> = view
+        .pixels()
+        .map(|c| {
+            let a = c[3] as f32 / 255.0;
+            [c[0] as f32 * a, c[1] as f32 * a, c[2] as f32 * a, a]
+        })
+        .collect();
+
+    // Horizontal pass: width x height -> new_width x height
+    let weights = filter_weights(width, new_width, filter);
+    let mut horizontal = vec![[0.0f32; 4]; new_width * height];
+    for y in 0..height {
+        let src = &premultiplied[y * width..(y + 1) * width];
+        for (x, (start, w)) in weights.iter().enumerate() {
+            horizontal[y * new_width + x] = weighted_sum(w.iter().enumerate().map(|(i, &w)| (src[start + i], w)));
+        }
+    }
+
+    // Vertical pass: new_width x height -> new_width x new_height
+    let weights = filter_weights(height, new_height, filter);
+    let mut out = Vec::with_capacity(new_width * new_height * 4);
+    for (start, w) in &weights {
+        for x in 0..new_width {
+            let [r, g, b, a] = weighted_sum(w.iter().enumerate().map(|(i, &w)| (horizontal[(start + i) * new_width + x], w)));
+            let a = a.clamp(0.0, 1.0);
+            if a > 0.0 {
+                out.extend([r, g, b].map(|c| (c / a).round().clamp(0.0, 255.0) as u8));
+            } else {
+                out.extend([0, 0, 0]);
+            }
+            out.push((a * 255.0).round() as u8);
+        }
+    }
+    out
+}
+
+/// For every destination coordinate, the first contributing source
+/// coordinate and the normalized weights of the contributing pixels.
+fn filter_weights(src_len: usize, dst_len: usize, filter: ResizeFilter) -> Vec<(usize, Vec<f32>)> {
+    let ratio = src_len as f32 / dst_len as f32;
+    // Widen the kernel when downscaling so every source pixel contributes.
+    let scale = ratio.max(1.0);
+    let support = filter.support() * scale;
+
+    (0..dst_len)
+        .map(|dst| {
+            let center = (dst as f32 + 0.5) * ratio;
+            if filter == ResizeFilter::Nearest {
+                return ((center as usize).min(src_len - 1), vec![1.0]);
+            }
+
+            let start = ((center - support).floor().max(0.0) as usize).min(src_len - 1);
+            let end = ((center + support).ceil() as usize).clamp(start + 1, src_len);
+            let mut weights: Vec<f32> = (start..end).map(|src| filter.kernel((src as f32 + 0.5 - center) / scale)).collect();
+            let sum: f32 = weights.iter().sum();
+            if sum.abs() > f32::EPSILON {
+                weights.iter_mut().for_each(|w| *w /= sum);
+            } else {
+                // Kernel fell between samples: use the closest source pixel.
+                let nearest = (center as usize).clamp(start, end - 1);
+                weights
+                    .iter_mut()
+                    .enumerate()
+                    .for_each(|(i, w)| *w = if start + i == nearest { 1.0 } else { 0.0 });
+            }
+            (start, weights)
+        })
+        .collect()
+}
+
+#[inline]
+fn weighted_sum(samples: impl Iterator<Item = ([f32; 4], f32)>) -> [f32; 4] {
+    samples.fold([0.0; 4], |mut acc, (pixel, w)| {
+        for (acc, c) in acc.iter_mut().zip(pixel) {
+            *acc += c * w;
+        }
+        acc
+    })
+}
+
+#[inline]
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let x = x * std::f32::consts::PI;
+        x.sin() / x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resize(rgba: &[u8], width: usize, height: usize, new_width: usize, new_height: usize, filter: ResizeFilter) -> Vec<u8> {
+        resize_rgba(
+            RgbaView::region(rgba, width, height, 0, 0, width, height).unwrap(),
+            new_width,
+            new_height,
+            filter,
+        )
+    }
+
+    #[test]
+    fn test_target_size() {
+        let square = PixelAspectRatio::Square;
+        assert_eq!(Resize::Exact { width: 10, height: 20 }.target_size(100, 100, square), (10, 20));
+        assert_eq!(
+            Resize::Fit {
+                max_width: 300,
+                max_height: 300
+            }
+            .target_size(6000, 4000, square),
+            (300, 200)
+        );
+        assert_eq!(
+            Resize::Fit {
+                max_width: 300,
+                max_height: 300
+            }
+            .target_size(30, 40, square),
+            (30, 40)
+        );
+        assert_eq!(Resize::Scale(3).target_size(16, 16, square), (48, 48));
+
+        // 2:1 pixels are twice as tall, so half the rows keep the proportions.
+        assert_eq!(Resize::Scale(1).target_size(100, 100, PixelAspectRatio::Ratio2To1), (100, 50));
+    }
+
+    #[test]
+    fn test_resize_filters() {
+        // Uniform images stay uniform with every filter, in both directions.
+        let rgba = [10u8, 120, 230, 255].repeat(6 * 4);
+        for filter in [ResizeFilter::Nearest, ResizeFilter::Bilinear, ResizeFilter::Box, ResizeFilter::Lanczos3] {
+            for (w, h) in [(2, 3), (13, 7)] {
+                let out = resize(&rgba, 6, 4, w, h, filter);
+                assert_eq!(out, [10u8, 120, 230, 255].repeat(w * h), "{filter:?} {w}x{h}");
+            }
+        }
+
+        // 4x1: black, black, white, white
+        let rgba = [[0, 0, 0, 255], [0, 0, 0, 255], [255, 255, 255, 255], [255, 255, 255, 255]].concat();
+        for filter in [ResizeFilter::Nearest, ResizeFilter::Box] {
+            let out = resize(&rgba, 4, 1, 2, 1, filter);
+            assert_eq!(out, [0, 0, 0, 255, 255, 255, 255, 255], "{filter:?}");
+        }
+
+        // Upscaling with nearest neighbor duplicates pixels.
+        let out = resize(&rgba, 4, 1, 8, 2, ResizeFilter::Nearest);
+        assert_eq!(&out[..16], &[0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255, 0, 0, 0, 255]);
+        assert_eq!(out[..32], out[32..]);
+
+        // Box averaging 2x2 -> 1x1
+        let out = resize(
+            &[[0, 0, 0, 255], [255, 255, 255, 255], [0, 0, 0, 255], [255, 255, 255, 255]].concat(),
+            2,
+            2,
+            1,
+            1,
+            ResizeFilter::Box,
+        );
+        assert_eq!(out, [128, 128, 128, 255]);
+    }
+
+    #[test]
+    fn test_transparent_pixels_do_not_bleed() {
+        // Opaque red next to fully transparent green
+        let rgba = [[255, 0, 0, 255], [0, 255, 0, 0]].concat();
+        let out = resize(&rgba, 2, 1, 1, 1, ResizeFilter::Box);
+        assert_eq!(&out[..3], &[255, 0, 0]);
+        assert_eq!(out[3], 128);
+    }
+}
@@ -132,6 +132,43 @@ impl PixelAspectRatio {
     }
 }
 
+/// A rectangle of pixels within an image.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Region {
+    /// Left edge in pixels
+    pub x: usize,
+    /// Top edge in pixels
+    pub y: usize,
+    /// Width in pixels
+    pub width: usize,
+    /// Height in pixels
+    pub height: usize,
+}
+
+impl Region {
+    /// Creates a region from its position and size.
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Self {
+        Self { x, y, width, height }
+    }
+}
+
+/// Where an image is shown in the terminal, used to position the cursor
+/// before redrawing a region of it.
+///
+/// Assumes the image is displayed at its pixel size, one image pixel per
+/// screen pixel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CellPlacement {
+    /// Width of a terminal cell in pixels
+    pub cell_width: usize,
+    /// Height of a terminal cell in pixels
+    pub cell_height: usize,
+    /// 1-based terminal column of the image's top-left corner
+    pub column: usize,
+    /// 1-based terminal row of the image's top-left corner
+    pub row: usize,
+}
+
 /// A decoded SIXEL image with full metadata.
 ///
 /// This struct contains the decoded pixel data along with additional
@@ -153,7 +190,9 @@ pub struct SixelImage {
 impl SixelImage {
     /// Decodes a complete ANSI SIXEL sequence.
     ///
-    /// This is the main entry point for decoding SIXEL graphics.
+    /// This is the main entry point for decoding SIXEL graphics. Both 7-bit
+    /// and 8-bit C1 controls are accepted, and sequences captured inside a
+    /// tmux passthrough wrapper are unwrapped first.
     #[must_use = "this returns the decoded SixelImage"]
     pub fn decode(data: &[u8]) -> Result<Self> {
         crate::decoder::decode_sixel(data)
@@ -291,7 +330,8 @@ impl SixelImage {
     ///
     /// let pixels = vec![255, 0, 0, 255]; // 1 red pixel
     /// let image = SixelImage::from_rgba(pixels, 1, 1);
-    /// let opts = EncodeOptions { max_colors: 16, ..Default::default() };
+    /// let mut opts = EncodeOptions::default();
+    /// opts.max_colors = 16;
     /// let sixel = image.encode_with(&opts)?;
     /// # Ok::<(), icy_sixel::SixelError>(())
     /// ```
@@ -300,6 +340,160 @@ impl SixelImage {
         crate::encoder::sixel_encode_impl(&self.pixels, self.width, self.height, opts, self.aspect_ratio, self.background_mode)
     }
 
+    /// Encodes this image with custom options into the bytes to send.
+    ///
+    /// Same as [`encode_with`](Self::encode_with) followed by
+    /// [`sixel_bytes`](crate::sixel_bytes): with
+    /// [`OutputFraming::c1_controls`](crate::OutputFraming::c1_controls) the
+    /// DCS and ST controls are the single bytes 0x90 and 0x9C rather than
+    /// their UTF-8 encoding.
+    ///
+    /// # Example
+    /// ```rust
+    /// use icy_sixel::{EncodeOptions, SixelImage};
+    ///
+    /// let image = SixelImage::from_rgba(vec![255, 0, 0, 255], 1, 1);
+    /// let mut opts = EncodeOptions::default();
+    /// opts.framing.c1_controls = true;
+    /// let bytes = image.encode_bytes(&opts)?;
+    /// assert_eq!((bytes[0], bytes[bytes.len() - 1]), (0x90, 0x9c));
+    /// # Ok::<(), icy_sixel::SixelError>(())
+    /// ```
+    #[must_use = "this returns the encoded SIXEL bytes"]
+    pub fn encode_bytes(&self, opts: &crate::encoder::EncodeOptions) -> Result<Vec<u8>> {
+        self.encode_with(opts).map(|sixel| crate::framing::sixel_bytes(&sixel))
+    }
+
+    /// Encodes this image with the best quality that fits into `budget`.
+    ///
+    /// Starting from `opts`, the color count and dithering strength (and the
+    /// image size, if [`ByteBudget::allow_downscale`](crate::ByteBudget::allow_downscale)
+    /// is set) are lowered until the output fits. Every candidate is encoded
+    /// for real, so this costs up to 27 regular encodes, and up to 63 when the
+    /// image has to be downscaled.
+    ///
+    /// Returns [`SixelError::BudgetExceeded`] if nothing fits.
+    ///
+    /// # Example
+    /// ```rust
+    /// use icy_sixel::{ByteBudget, EncodeOptions, SixelImage};
+    ///
+    /// let pixels: Vec<u8> = (0..64 * 64).flat_map(|i| [(i % 64 * 4) as u8, (i / 64 * 4) as u8, 128, 255]).collect();
+    /// let image = SixelImage::from_rgba(pixels, 64, 64);
+    /// let encoded = image.encode_to_budget(ByteBudget::new(8_000), &EncodeOptions::default())?;
+    /// assert!(encoded.sixel.len() <= 8_000);
+    /// println!("{} colors, diffusion {}", encoded.options.max_colors, encoded.options.diffusion);
+    /// # Ok::<(), icy_sixel::SixelError>(())
+    /// ```
+    pub fn encode_to_budget(&self, budget: crate::ByteBudget, opts: &crate::encoder::EncodeOptions) -> Result<crate::BudgetedEncoding> {
+        let view = crate::encoder::RgbaView::whole(&self.pixels, self.width, self.height)?;
+        crate::budget::encode_to_budget(view, opts, self.aspect_ratio, self.background_mode, budget)
+    }
+
+    /// Encodes this image with a palette per group of `bands_per_palette`
+    /// bands (see [`ColorMode::HighColor`](crate::ColorMode::HighColor)) and
+    /// reports the size compared with a single palette.
+    ///
+    /// The image is encoded twice, with `opts` in both cases except for the
+    /// color mode.
+    ///
+    /// # Example
+    /// ```rust
+    /// use icy_sixel::{EncodeOptions, SixelImage};
+    ///
+    /// let pixels: Vec<u8> = (0..64 * 48).flat_map(|i| [(i % 64 * 4) as u8, (i / 64 * 5) as u8, 128, 255]).collect();
+    /// let image = SixelImage::from_rgba(pixels, 64, 48);
+    /// let mut opts = EncodeOptions::default();
+    /// opts.max_colors = 16;
+    /// let encoded = image.encode_high_color(1, &opts)?;
+    /// println!("{} bytes, {:.0}% of a single palette", encoded.sixel.len(), encoded.size_ratio() * 100.0);
+    /// # Ok::<(), icy_sixel::SixelError>(())
+    /// ```
+    pub fn encode_high_color(&self, bands_per_palette: usize, opts: &crate::encoder::EncodeOptions) -> Result<crate::HighColorEncoding> {
+        let high_color = crate::encoder::EncodeOptions {
+            color_mode: crate::ColorMode::HighColor { bands_per_palette },
+            ..opts.clone()
+        };
+        let single_palette = crate::encoder::EncodeOptions {
+            color_mode: crate::ColorMode::Color,
+            ..opts.clone()
+        };
+        Ok(crate::HighColorEncoding {
+            sixel: self.encode_with(&high_color)?,
+            single_palette_bytes: self.encode_with(&single_palette)?.len(),
+        })
+    }
+
+    /// Encodes only `region` of this image, e.g. the part that changed.
+    ///
+    /// The region is encoded directly from the image's pixel buffer without
+    /// copying it out first.
+    ///
+    /// # Example
+    /// ```rust
+    /// use icy_sixel::{EncodeOptions, Region, SixelImage};
+    ///
+    /// let image = SixelImage::from_rgba(vec![255; 64 * 64 * 4], 64, 64);
+    /// let sixel = image.encode_region(Region::new(16, 16, 32, 8), &EncodeOptions::default())?;
+    /// # Ok::<(), icy_sixel::SixelError>(())
+    /// ```
+    #[must_use = "this returns the encoded SIXEL string"]
+    pub fn encode_region(&self, region: Region, opts: &crate::encoder::EncodeOptions) -> Result<String> {
+        let view = crate::encoder::RgbaView::region(&self.pixels, self.width, self.height, region.x, region.y, region.width, region.height)?;
+        crate::encoder::sixel_encode_view(view, opts, self.aspect_ratio, self.background_mode)
+    }
+
+    /// Encodes `region` of this image, prefixed with the CSI cursor position
+    /// sequence that places it on top of the image shown at `placement`.
+    ///
+    /// SIXEL images start at the top-left corner of the cursor cell, so the
+    /// region is first extended up and left to the nearest cell boundary.
+    /// The cell math needs one image pixel per screen pixel: a square pixel
+    /// aspect ratio and no [`EncodeOptions::resize`](crate::EncodeOptions::resize)
+    /// or [`EncodeOptions::printer`](crate::EncodeOptions::printer), otherwise
+    /// [`SixelError::UnsupportedOptions`] is returned.
+    ///
+    /// # Example
+    /// ```rust
+    /// use icy_sixel::{CellPlacement, EncodeOptions, Region, SixelImage};
+    ///
+    /// let image = SixelImage::from_rgba(vec![255; 80 * 40 * 4], 80, 40);
+    /// let placement = CellPlacement { cell_width: 10, cell_height: 20, column: 1, row: 1 };
+    /// let sixel = image.encode_region_at(Region::new(35, 20, 10, 10), placement, &EncodeOptions::default())?;
+    /// assert!(sixel.starts_with("\x1b[2;4H"));
+    /// # Ok::<(), icy_sixel::SixelError>(())
+    /// ```
+    #[must_use = "this returns the encoded SIXEL string"]
+    pub fn encode_region_at(&self, region: Region, placement: CellPlacement, opts: &crate::encoder::EncodeOptions) -> Result<String> {
+        if placement.cell_width == 0 || placement.cell_height == 0 {
+            return Err(SixelError::InvalidDimensions {
+                width: placement.cell_width,
+                height: placement.cell_height,
+            });
+        }
+        if !self.aspect_ratio.is_square() || opts.resize.is_some() || opts.printer.is_some() {
+            return Err(SixelError::UnsupportedOptions(
+                "cursor placement needs square pixels without resizing or printer framing",
+            ));
+        }
+        let x = region.x - region.x % placement.cell_width;
+        let y = region.y - region.y % placement.cell_height;
+        let invalid = || SixelError::InvalidRegion {
+            x: region.x,
+            y: region.y,
+            width: region.width,
+            height: region.height,
+        };
+        let width = region.width.checked_add(region.x - x).ok_or_else(invalid)?;
+        let height = region.height.checked_add(region.y - y).ok_or_else(invalid)?;
+        let aligned = Region::new(x, y, width, height);
+        let sixel = self.encode_region(aligned, opts)?;
+
+        let row = placement.row.max(1) + y / placement.cell_height;
+        let column = placement.column.max(1) + x / placement.cell_width;
+        Ok(format!("\x1b[{row};{column}H{sixel}"))
+    }
+
     /// Returns the image dimensions as a tuple (width, height).
     #[inline]
     pub fn dimensions(&self) -> (usize, usize) {
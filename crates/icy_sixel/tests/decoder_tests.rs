@@ -170,6 +170,20 @@ fn test_decode_raster_attributes() {
     assert!(height >= 20, "Height should be at least 20");
 }
 
+#[test]
+fn test_decode_opaque_background_uses_defined_register_0() {
+    // Opaque (P2=0): undrawn pixels take register 0 as defined in the image,
+    // including the very first one.
+    let sixel_data = b"\x1bP0;0;0q#0;2;0;0;100#1;2;100;0;0\"1;1;4;6#1?~~\x1b\\";
+
+    let image = SixelImage::decode(sixel_data).expect("decode");
+    assert_eq!((image.width, image.height), (4, 6));
+    let pixel = |x: usize, y: usize| &image.pixels[(y * image.width + x) * 4..][..4];
+    assert_eq!(pixel(0, 0), [0, 0, 255, 255]);
+    assert_eq!(pixel(1, 0), [255, 0, 0, 255]);
+    assert_eq!(pixel(3, 5), [0, 0, 255, 255]);
+}
+
 #[test]
 fn test_decode_empty() {
     // Empty SIXEL
@@ -533,3 +547,375 @@ fn test_encode_beelitz_heilstaetten_png() {
     assert_eq!(decoded_width, width as usize, "Decoded width should match");
     assert!(decoded_height >= height as usize, "Decoded height should be >= original");
 }
+
+#[test]
+fn test_roundtrip_exact_colors_is_lossless() {
+    // A 40x20 "pixel art" image with 12 distinct colors and a transparent hole.
+    let colors: [[u8; 3]; 12] = [
+        [0, 0, 0],
+        [255, 255, 255],
+        [255, 0, 0],
+        [0, 255, 0],
+        [0, 0, 255],
+        [17, 34, 51],
+        [200, 100, 50],
+        [128, 128, 128],
+        [1, 2, 3],
+        [254, 253, 252],
+        [90, 180, 45],
+        [33, 66, 99],
+    ];
+    let (width, height) = (40usize, 20usize);
+    let mut rgba = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        for x in 0..width {
+            let c = colors[(x / 4 + y / 5) % colors.len()];
+            let alpha = if (10..14).contains(&x) && (5..9).contains(&y) { 0 } else { 255 };
+            rgba.extend_from_slice(&[c[0], c[1], c[2], alpha]);
+        }
+    }
+
+    let image = SixelImage::from_rgba(rgba.clone(), width, height);
+    let mut opts = EncodeOptions::default();
+    opts.max_colors = 16;
+    let sixel = image.encode_with(&opts).expect("encode");
+    let decoded = SixelImage::decode(sixel.as_bytes()).expect("decode");
+    assert_eq!(decoded.width, width);
+    assert!(decoded.height >= height, "Decoded height may be padded to the 6-pixel band");
+
+    for (i, (orig, dec)) in rgba.chunks_exact(4).zip(decoded.pixels.chunks_exact(4)).enumerate() {
+        if orig[3] == 0 {
+            assert_eq!(dec[3], 0, "pixel {} should stay transparent", i);
+            continue;
+        }
+        // Colors are only quantized to the SIXEL percent grid (0-100).
+        for c in 0..3 {
+            let diff = (orig[c] as i32 - dec[c] as i32).abs();
+            assert!(diff <= 3, "pixel {} channel {}: {} vs {}", i, c, orig[c], dec[c]);
+        }
+    }
+
+    // Without dithering noise every 4-pixel column block is a single color,
+    // so the palette holds exactly the 12 source colors.
+    assert_eq!(sixel.matches(";2;").count(), colors.len());
+}
+
+#[test]
+fn test_roundtrip_resize() {
+    let (width, height) = (120usize, 60usize);
+    let rgba: Vec<u8> = (0..width * height)
+        .flat_map(|i| [(i % width * 2) as u8, (i / width * 4) as u8, 128, 255])
+        .collect();
+    let image = SixelImage::from_rgba(rgba, width, height);
+
+    let mut opts = EncodeOptions::default();
+    opts.resize = Some(Resize::Fit { max_width: 30, max_height: 30 });
+    let decoded = SixelImage::decode(image.encode_with(&opts).expect("encode").as_bytes()).expect("decode");
+    assert_eq!(decoded.width, 30);
+    assert_eq!(decoded.height, 18, "15 rows, padded to the 6-pixel band");
+
+    // 2:1 pixels: half the rows, the terminal stretches them back.
+    let image = image.with_aspect_ratio(PixelAspectRatio::Ratio2To1);
+    let mut opts = EncodeOptions::default();
+    opts.resize = Some(Resize::Scale(1));
+    opts.resize_filter = ResizeFilter::Box;
+    let decoded = SixelImage::decode(image.encode_with(&opts).expect("encode").as_bytes()).expect("decode");
+    assert_eq!(decoded.width, width);
+    assert_eq!(decoded.height, height / 2);
+    assert_eq!(decoded.corrected_dimensions(), (width, height));
+}
+
+#[test]
+fn test_roundtrip_region() {
+    // Red image with a blue 8x6 block at (16, 12)
+    let (width, height) = (40usize, 30usize);
+    let rgba: Vec<u8> = (0..width * height)
+        .flat_map(|i| {
+            let (x, y) = (i % width, i / width);
+            if (16..24).contains(&x) && (12..18).contains(&y) {
+                [0, 0, 255, 255]
+            } else {
+                [255, 0, 0, 255]
+            }
+        })
+        .collect();
+    let image = SixelImage::from_rgba(rgba, width, height);
+
+    let sixel = image.encode_region(Region::new(14, 12, 12, 6), &EncodeOptions::default()).expect("encode");
+    let decoded = SixelImage::decode(sixel.as_bytes()).expect("decode");
+    assert_eq!((decoded.width, decoded.height), (12, 6));
+    for (i, pixel) in decoded.pixels.chunks_exact(4).enumerate() {
+        let blue = (2..10).contains(&(i % 12));
+        assert_eq!(pixel[2] > 128, blue, "pixel {} is {:?}", i, pixel);
+    }
+
+    assert!(matches!(
+        image.encode_region(Region::new(30, 0, 11, 1), &EncodeOptions::default()),
+        Err(SixelError::InvalidRegion { .. })
+    ));
+    assert!(image.encode_region(Region::new(0, 0, 0, 1), &EncodeOptions::default()).is_err());
+
+    // Cursor placement: the region grows to the cell at column 2, row 3 of an image placed at (5, 2).
+    let placement = CellPlacement {
+        cell_width: 8,
+        cell_height: 6,
+        column: 5,
+        row: 2,
+    };
+    let sixel = image
+        .encode_region_at(Region::new(16, 13, 4, 4), placement, &EncodeOptions::default())
+        .expect("encode");
+    assert!(sixel.starts_with("\x1b[4;7H\x1bP"), "{:?}", &sixel[..12]);
+    let decoded = SixelImage::decode(sixel.as_bytes()).expect("decode");
+    assert_eq!((decoded.width, decoded.height), (4, 6));
+
+    // Sizes that overflow once aligned are invalid regions, not panics.
+    assert!(matches!(
+        image.encode_region_at(Region::new(17, 13, usize::MAX, 4), placement, &EncodeOptions::default()),
+        Err(SixelError::InvalidRegion { .. })
+    ));
+    assert!(matches!(
+        image.encode_region_at(Region::new(16, 13, 4, usize::MAX), placement, &EncodeOptions::default()),
+        Err(SixelError::InvalidRegion { .. })
+    ));
+
+    // Resized or non-square pixels don't map onto the cell grid.
+    let mut resized = EncodeOptions::default();
+    resized.resize = Some(Resize::Scale(2));
+    let mut printer = EncodeOptions::default();
+    printer.printer = Some(PrinterOptions::default());
+    for opts in [resized, printer] {
+        assert!(matches!(
+            image.encode_region_at(Region::new(16, 13, 4, 4), placement, &opts),
+            Err(SixelError::UnsupportedOptions(_))
+        ));
+    }
+    let tall = image.clone().with_aspect_ratio(PixelAspectRatio::Ratio2To1);
+    assert!(matches!(
+        tall.encode_region_at(Region::new(16, 13, 4, 4), placement, &EncodeOptions::default()),
+        Err(SixelError::UnsupportedOptions(_))
+    ));
+}
+
+#[test]
+fn test_roundtrip_indexed_image() {
+    // Colors that quantization would likely merge stay separate registers.
+    let palette = vec![Rgb::new(10, 20, 30), Rgb::new(12, 22, 33), Rgb::new(250, 128, 0), Rgb::new(255, 0, 255)];
+    let (width, height) = (16usize, 12usize);
+    let indices: Vec<u8> = (0..width * height).map(|i| ((i % width / 4 + i / width / 6) % 4) as u8).collect();
+
+    let image = IndexedImage::try_from_indexed(palette.clone(), indices.clone(), width, height, Some(3)).expect("valid image");
+    let sixel = image.encode().expect("encode");
+    assert_eq!(sixel.matches(";2;").count(), palette.len());
+
+    let decoded = SixelImage::decode(sixel.as_bytes()).expect("decode");
+    assert_eq!((decoded.width, decoded.height), (width, height));
+    for (&index, pixel) in indices.iter().zip(decoded.pixels.chunks_exact(4)) {
+        if index == 3 {
+            assert_eq!(pixel[3], 0, "transparent index must stay undrawn");
+            continue;
+        }
+        let expected = palette[index as usize];
+        for (&a, b) in pixel.iter().zip([expected.r, expected.g, expected.b]) {
+            assert!((a as i32 - b as i32).abs() <= 2, "{:?} vs {:?}", pixel, expected);
+        }
+    }
+
+    assert!(matches!(
+        IndexedImage::try_from_indexed(palette.clone(), vec![4; width * height], width, height, None),
+        Err(SixelError::InvalidPalette(_))
+    ));
+    assert!(matches!(
+        IndexedImage::try_from_indexed(Vec::new(), vec![0; width * height], width, height, None),
+        Err(SixelError::InvalidPalette(_))
+    ));
+    assert!(IndexedImage::try_from_indexed(palette, vec![0; 5], width, height, None).is_err());
+}
+
+#[test]
+fn test_roundtrip_output_framing() {
+    let (width, height) = (40usize, 13usize);
+    let rgba: Vec<u8> = (0..width * height)
+        .flat_map(|i| [(i % width * 6) as u8, (i / width * 19) as u8, 128, 255])
+        .collect();
+    let image = SixelImage::from_rgba(rgba, width, height);
+    let plain = image.encode().expect("encode");
+    let expected = SixelImage::decode(plain.as_bytes()).expect("decode");
+
+    let framings = [
+        OutputFraming {
+            passthrough: Some(Passthrough::Tmux),
+            ..Default::default()
+        },
+        OutputFraming {
+            c1_controls: true,
+            ..Default::default()
+        },
+        OutputFraming {
+            line_length: Some(72),
+            ..Default::default()
+        },
+        OutputFraming {
+            passthrough: Some(Passthrough::Tmux),
+            c1_controls: true,
+            line_length: Some(16),
+        },
+    ];
+    for framing in framings {
+        let mut opts = EncodeOptions::default();
+        opts.framing = framing;
+        let sixel = image.encode_with(&opts).expect("encode");
+        let decoded = SixelImage::decode(sixel.as_bytes()).expect("decode");
+        assert_eq!(decoded.pixels, expected.pixels, "{framing:?}");
+
+        if framing.c1_controls {
+            assert!(sixel.contains('\u{90}') && sixel.contains('\u{9c}'));
+            // Raw 8-bit bytes decode as well.
+            let bytes = image.encode_bytes(&opts).expect("encode");
+            assert_eq!(bytes.len(), sixel.chars().count());
+            assert!(!bytes.contains(&0xc2));
+            assert_eq!(SixelImage::decode(&bytes).expect("decode").pixels, expected.pixels);
+        }
+        if let (Some(line_length), None) = (framing.line_length, framing.passthrough) {
+            assert!(sixel.lines().count() > 1);
+            assert!(sixel.lines().all(|line| line.chars().count() <= line_length));
+        }
+    }
+}
+
+#[test]
+fn test_encode_to_budget() {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    let (width, height) = (96usize, 48usize);
+    let pixels: Vec<u8> = (0..width * height)
+        .flat_map(|i| {
+            let (x, y) = (i % width, i / width);
+            [(x * 255 / width) as u8, (y * 255 / height) as u8, ((x + y) * 3) as u8, 255]
+        })
+        .collect();
+    let image = SixelImage::from_rgba(pixels, width, height);
+    let full = image.encode().expect("encode");
+
+    let budget = full.len() / 3;
+    let encoded = image.encode_to_budget(ByteBudget::new(budget), &EncodeOptions::default()).expect("fits");
+    assert!(encoded.sixel.len() <= budget);
+    assert!(encoded.options.max_colors < 256);
+    // The chosen options reproduce the result.
+    assert_eq!(image.encode_with(&encoded.options).expect("encode"), encoded.sixel);
+    let decoded = SixelImage::decode(encoded.sixel.as_bytes()).expect("decode");
+    assert_eq!((decoded.width, decoded.height), (width, height));
+
+    // A generous budget keeps the requested options.
+    let encoded = image.encode_to_budget(ByteBudget::new(full.len()), &EncodeOptions::default()).expect("fits");
+    assert_eq!(encoded.sixel, full);
+
+    // Too small for any color count at full size, but fits once downscaled.
+    let budget = 200;
+    assert!(matches!(
+        image.encode_to_budget(ByteBudget::new(budget), &EncodeOptions::default()),
+        Err(SixelError::BudgetExceeded { max_bytes: 200 })
+    ));
+    let budget = ByteBudget {
+        allow_downscale: true,
+        ..ByteBudget::new(budget)
+    };
+    // Every encode reports its first band once; the search is bounded.
+    let encodes = Arc::new(AtomicUsize::new(0));
+    let counter = encodes.clone();
+    let mut opts = EncodeOptions::default();
+    opts.progress = ProgressHook::new().with_callback(move |p| {
+        if p.stage == EncodeStage::Bands && p.done == 0 {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    let encoded = image.encode_to_budget(budget, &opts).expect("fits downscaled");
+    assert!(encoded.sixel.len() <= 200);
+    assert!(matches!(encoded.options.resize, Some(Resize::Exact { width, .. }) if width < 96));
+    assert!(encodes.load(Ordering::Relaxed) <= 63, "{} encodes", encodes.load(Ordering::Relaxed));
+
+    // Even a single pixel doesn't fit: fails after the full-size search and one probe.
+    encodes.store(0, Ordering::Relaxed);
+    let budget = ByteBudget {
+        allow_downscale: true,
+        ..ByteBudget::new(10)
+    };
+    assert!(matches!(
+        image.encode_to_budget(budget, &opts),
+        Err(SixelError::BudgetExceeded { max_bytes: 10 })
+    ));
+    assert!(encodes.load(Ordering::Relaxed) <= 28, "{} encodes", encodes.load(Ordering::Relaxed));
+}
+
+#[test]
+fn test_kmeans_is_reproducible() {
+    let (width, height) = (80usize, 30usize);
+    let pixels: Vec<u8> = (0..width * height)
+        .flat_map(|i| {
+            let (x, y) = (i % width, i / width);
+            let noise = (i * 2654435761 % 97) as u8;
+            [(x * 3) as u8 ^ noise, (y * 8) as u8, noise.wrapping_mul(2), if x < 4 { 0 } else { 255 }]
+        })
+        .collect();
+    let image = SixelImage::from_rgba(pixels, width, height);
+
+    for kmeans in [KmeansOptions::new(), KmeansOptions::new().seed(7).sampling_factor(0.1).max_samples(500)] {
+        for color_space in [ColorSpace::Oklab, ColorSpace::Lab] {
+            let mut opts = EncodeOptions::default();
+            opts.max_colors = 16;
+            opts.quantize_method = QuantizeMethod::Kmeans(kmeans);
+            opts.color_space = color_space;
+            let first = image.encode_with(&opts).expect("encode");
+            let second = image.encode_with(&opts).expect("encode");
+            assert_eq!(first, second, "{kmeans:?} {color_space:?}");
+            SixelImage::decode(first.as_bytes()).expect("decode");
+        }
+    }
+}
+
+#[test]
+fn test_encode_progress_and_cancel() {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    };
+
+    let (width, height) = (40usize, 30usize);
+    let pixels: Vec<u8> = (0..width * height)
+        .flat_map(|i| [(i % width * 6) as u8, (i / width * 8) as u8, 90, if i % 7 == 0 { 0 } else { 255 }])
+        .collect();
+    let image = SixelImage::from_rgba(pixels, width, height);
+
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let log = reports.clone();
+    let mut opts = EncodeOptions::default();
+    opts.max_colors = 16;
+    opts.progress = ProgressHook::new().with_callback(move |p| log.lock().unwrap().push(p));
+    let sixel = image.encode_with(&opts).expect("encode");
+    opts.progress = ProgressHook::new();
+    let plain = image.encode_with(&opts).expect("encode");
+    assert_eq!(sixel, plain, "the hook must not change the output");
+
+    let reports = reports.lock().unwrap();
+    assert_eq!(reports[0].stage, EncodeStage::Quantize);
+    assert!(reports.iter().any(|p| p.stage == EncodeStage::Dither));
+    let bands: Vec<usize> = reports.iter().filter(|p| p.stage == EncodeStage::Bands).map(|p| p.done).collect();
+    assert_eq!(bands, (0..=5).collect::<Vec<_>>());
+    assert_eq!(reports.last().unwrap().total, 5);
+
+    // Setting the flag halfway through the bands stops the encode.
+    let cancel = Arc::new(AtomicBool::new(false));
+    let flag = cancel.clone();
+    let mut opts = EncodeOptions::default();
+    opts.progress = ProgressHook::new().with_cancel_flag(cancel).with_callback(move |p| {
+        if p.stage == EncodeStage::Bands && p.done == 2 {
+            flag.store(true, Ordering::Relaxed);
+        }
+    });
+    assert!(matches!(image.encode_with(&opts), Err(SixelError::Cancelled)));
+    assert!(opts.progress.is_cancelled());
+    assert!(matches!(image.encode_to_budget(ByteBudget::new(100_000), &opts), Err(SixelError::Cancelled)));
+}